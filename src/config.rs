@@ -56,6 +56,22 @@ depot = 'android'
 # regex = '^woodly/'
 # replacement = 'doodly/'
 
+# Fetch projects without their file contents (`git fetch --filter=blob:none`), and fetch them on demand when they're
+# checked out instead. This saves a lot of space and bandwidth for huge repositories, but contents fetched on demand
+# are stored in each tree rather than shared through the depot, and commands that read old file contents might fail.
+# partial_clone = false
+
+# Alternatively, use partial clones only for projects matching any of these regexes.
+# partial_clone_projects = ['^platform/prebuilts/']
+
+# Domain appended to reviewers, CCs and users to notify that are given to `pore upload` without one.
+# Defaults to 'google.com' for googlesource.com remotes.
+email_domain = 'google.com'
+
+# Aliases that expand to lists of users when passed to `pore upload --re`, `--cc` or `--notify-to`.
+# [remotes.reviewer_aliases]
+# build-team = ['alice', 'bob@example.com']
+
 [[manifests]]
 # Name of the manifest: used in `pore clone MANIFEST[/BRANCH]`
 name = 'aosp'
@@ -156,6 +172,65 @@ pub struct RemoteConfig {
 
   #[serde(default = "default_upload_options")]
   pub default_upload_options: Vec<String>,
+
+  #[serde(default)]
+  pub partial_clone: bool,
+
+  #[serde(default, with = "serde_regex")]
+  pub partial_clone_projects: Vec<Regex>,
+
+  #[serde(default)]
+  pub email_domain: Option<String>,
+
+  #[serde(default)]
+  pub reviewer_aliases: BTreeMap<String, Vec<String>>,
+}
+
+impl RemoteConfig {
+  /// Get the domain to add to users without one, which defaults to google.com for googlesource.com remotes.
+  pub fn email_domain(&self) -> Option<&str> {
+    match &self.email_domain {
+      Some(domain) => Some(domain),
+      None if self.url.contains(".googlesource.com") => Some("google.com"),
+      None => None,
+    }
+  }
+
+  /// Expand aliases and add the default email domain to a list of users.
+  pub fn resolve_users(&self, users: &[String]) -> Result<Vec<String>, Error> {
+    let mut result = Vec::new();
+    for user in users {
+      let expanded = match self.reviewer_aliases.get(user) {
+        Some(alias) => alias.as_slice(),
+        None => std::slice::from_ref(user),
+      };
+
+      for user in expanded {
+        let user = if user.contains('@') {
+          user.clone()
+        } else {
+          match self.email_domain() {
+            Some(domain) => format!("{}@{}", user, domain),
+            None => bail!(
+              "user '{}' has no domain, and remote {} has no email_domain configured",
+              user,
+              self.name
+            ),
+          }
+        };
+
+        if !result.contains(&user) {
+          result.push(user);
+        }
+      }
+    }
+    Ok(result)
+  }
+
+  /// Check whether a project should be fetched without its file contents.
+  pub fn is_partial_clone(&self, project: &str) -> bool {
+    self.partial_clone || self.partial_clone_projects.iter().any(|regex| regex.is_match(project))
+  }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
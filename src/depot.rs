@@ -15,6 +15,7 @@
  */
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::{Path, PathBuf};
 
 use filetime::FileTime;
@@ -28,13 +29,23 @@ use super::util;
 
 #[derive(Clone, Debug)]
 pub struct Depot {
-  #[allow(dead_code)]
   pub name: String,
   pub path: PathBuf,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct ProjectName(String);
 
+/// Name of the remote that partial clone checkouts fetch missing objects from.
+const PROMISOR_REMOTE: &str = "pore-promisor";
+
+impl fmt::Display for ProjectName {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let ProjectName(project) = self;
+    write!(f, "{}", project)
+  }
+}
+
 impl Depot {
   pub fn new(name: String, path: PathBuf) -> Result<Depot, Error> {
     Ok(Depot { name, path })
@@ -166,25 +177,148 @@ impl Depot {
   }
 
   pub fn objects_mirror(&self, _remote_config: &config::RemoteConfig, project: &ProjectName) -> PathBuf {
+    self.project_objects_mirror(project)
+  }
+
+  fn project_objects_mirror(&self, project: &ProjectName) -> PathBuf {
     let ProjectName(project) = project;
     let repo_name: String = format!("{}.git", project);
     self.path.join("objects").join(repo_name)
   }
 
   pub fn refs_mirror(&self, remote_config: &config::RemoteConfig, project: &ProjectName) -> PathBuf {
-    let remote: &str = remote_config.name.as_ref();
+    self.remote_refs_mirror(&remote_config.name, project)
+  }
+
+  fn remote_refs_mirror(&self, remote: &str, project: &ProjectName) -> PathBuf {
     let ProjectName(project) = project;
     let repo_name: String = format!("{}.git", project);
     self.path.join("refs").join(remote).join(repo_name)
   }
 
+  /// List the projects that have an objects mirror in the depot.
+  pub fn projects(&self) -> Result<Vec<ProjectName>, Error> {
+    Depot::find_mirrors(&self.path.join("objects"))
+  }
+
+  /// List the projects that have a refs mirror for a remote in the depot.
+  pub fn remote_projects(&self, remote: &str) -> Result<Vec<ProjectName>, Error> {
+    Depot::find_mirrors(&self.path.join("refs").join(remote))
+  }
+
+  fn find_mirrors(root: &Path) -> Result<Vec<ProjectName>, Error> {
+    if !root.exists() {
+      return Ok(Vec::new());
+    }
+
+    let mut result = Vec::new();
+    let mut walker = walkdir::WalkDir::new(root).min_depth(1).sort_by_file_name().into_iter();
+    while let Some(entry) = walker.next() {
+      let entry = entry.with_context(|| format!("failed to walk {:?}", root))?;
+      if !entry.file_type().is_dir() {
+        continue;
+      }
+
+      let relpath = entry.path().strip_prefix(root).unwrap();
+      let relpath = relpath
+        .to_str()
+        .ok_or_else(|| format_err!("invalid project path {:?}", relpath))?;
+      if let Some(project) = relpath.strip_suffix(".git") {
+        result.push(ProjectName(project.to_string()));
+        walker.skip_current_dir();
+      }
+    }
+    Ok(result)
+  }
+
+  /// List the remotes that have refs mirrors in the depot.
+  pub fn remotes(&self) -> Result<Vec<String>, Error> {
+    let refs_path = self.path.join("refs");
+    if !refs_path.exists() {
+      return Ok(Vec::new());
+    }
+
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(&refs_path).with_context(|| format!("failed to read {:?}", refs_path))? {
+      let entry = entry.with_context(|| format!("failed to read {:?}", refs_path))?;
+      if entry.file_type()?.is_dir() {
+        if let Some(remote) = entry.file_name().to_str() {
+          result.push(remote.to_string());
+        }
+      }
+    }
+    result.sort();
+    Ok(result)
+  }
+
+  /// Check whether the objects mirror of a project is a partial clone, lacking the contents of some files.
+  pub fn is_partial(&self, remote_config: &config::RemoteConfig, project: &ProjectName) -> bool {
+    // Read the config directly, to avoid opening the whole repository.
+    let config_path = self.objects_mirror(remote_config, project).join("config");
+    git2::Config::open(&config_path)
+      .and_then(|config| config.get_string("extensions.partialclone"))
+      .is_ok()
+  }
+
+  /// Configure a repository to fetch missing objects from a remote on demand.
+  fn set_promisor(config: &mut git2::Config, remote: &str) -> Result<(), Error> {
+    config.set_i32("core.repositoryformatversion", 1)?;
+    config.set_str("extensions.partialclone", remote)?;
+    config.set_bool(&format!("remote.{}.promisor", remote), true)?;
+    config.set_str(&format!("remote.{}.partialclonefilter", remote), "blob:none")?;
+    Ok(())
+  }
+
+  /// Check whether the objects mirror of a project is a shallow clone, lacking some of its history.
+  pub fn is_shallow(&self, remote_config: &config::RemoteConfig, project: &ProjectName) -> bool {
+    self.objects_mirror(remote_config, project).join("shallow").exists()
+  }
+
+  /// Copy the list of shallow commits from one repository to another.
+  ///
+  /// Repositories that use a shallow mirror as an alternate need to know where its history ends.
+  fn replace_shallow<T: AsRef<Path>>(src: T, dst: T) -> Result<(), Error> {
+    let src = src.as_ref().join("shallow");
+    let dst = dst.as_ref().join("shallow");
+    if src.exists() {
+      std::fs::copy(&src, &dst).with_context(|| format!("failed to copy {:?} to {:?}", src, dst))?;
+    } else if dst.exists() {
+      std::fs::remove_file(&dst).with_context(|| format!("failed to remove {:?}", dst))?;
+    }
+    Ok(())
+  }
+
   pub fn fetch_repo(
     &self,
     remote_config: &config::RemoteConfig,
     project: &str,
     targets: Option<&[String]>,
     fetch_tags: bool,
-    depth: Option<i32>,
+    depth: Option<u32>,
+  ) -> Result<(), Error> {
+    self.fetch_impl(remote_config, project, targets, fetch_tags, depth, false)
+  }
+
+  /// Fetch every branch of a project from scratch, without trusting the objects that are already in the mirror.
+  pub fn refetch_repo(&self, remote_config: &config::RemoteConfig, project: &str) -> Result<(), Error> {
+    // The depth that the mirror was originally fetched with isn't recorded, so refetch shallow mirrors with
+    // only the tips of their branches rather than unexpectedly fetching their entire history.
+    let depth = if self.is_shallow(remote_config, &Depot::apply_project_renames(remote_config, project)) {
+      Some(1)
+    } else {
+      None
+    };
+    self.fetch_impl(remote_config, project, None, false, depth, true)
+  }
+
+  fn fetch_impl(
+    &self,
+    remote_config: &config::RemoteConfig,
+    project: &str,
+    targets: Option<&[String]>,
+    fetch_tags: bool,
+    depth: Option<u32>,
+    refetch: bool,
   ) -> Result<(), Error> {
     ensure!(!project.starts_with('/'), "invalid project path {}", project);
     ensure!(!project.ends_with('/'), "invalid project path {}", project);
@@ -212,6 +346,11 @@ impl Depot {
       .with_context(|| format!("failed to get config for repo at {:?}", objects_path))?;
     config.set_i32("gc.auto", 0).context("failed to set gc.auto")?;
 
+    let partial = remote_config.is_partial_clone(project);
+    if partial {
+      Depot::set_promisor(&mut config, &remote_config.name).context("failed to configure partial clone")?;
+    }
+
     // Always use git directly.
     // libgit2 sometimes has pathologically bad performance while fetching some repositories.
     // We don't lose that much from shelling out to git to fetch, since we're mostly bound on bandwidth.
@@ -222,9 +361,32 @@ impl Depot {
       cmd.arg("--tags");
     }
 
-    if let Some(depth) = depth {
-      cmd.arg("--depth");
-      cmd.arg(depth.to_string());
+    if refetch {
+      cmd.arg("--refetch");
+    }
+
+    if partial {
+      cmd.arg("--filter=blob:none");
+    }
+
+    // Only fetch shallowly into a mirror that doesn't already have complete history, since fetching with
+    // --depth into a complete mirror would truncate it. If full history is wanted, deepen shallow mirrors.
+    let shallow = self.is_shallow(remote_config, &local_project);
+    let populated = objects_repo
+      .references()
+      .context("failed to list references")?
+      .next()
+      .is_some();
+    match depth {
+      Some(depth) if shallow || !populated => {
+        cmd.arg("--depth");
+        cmd.arg(depth.to_string());
+      }
+      Some(_) => {}
+      None if shallow => {
+        cmd.arg("--unshallow");
+      }
+      None => {}
     }
 
     if let Some(targets) = targets {
@@ -269,14 +431,443 @@ impl Depot {
     let refs_tags = refs_path.join("refs").join("tags");
     Depot::replace_dir(&objects_tags, &refs_tags).context("failed to replace tags")?;
 
+    Depot::replace_shallow(&objects_path, &refs_path).context("failed to replace shallow")?;
+
     dir.unlock().context("failed to unlock directory")?;
     Ok(())
   }
 
+  /// Repack the objects mirror of a project.
+  ///
+  /// Refs mirrors for every remote and the checkouts of trees (`checkouts` maps alternate objects directories to the
+  /// git directories that use them) rely on the objects mirror, so everything reachable from them is packed, by
+  /// temporarily recording their refs in the objects mirror for the duration of the repack. Anything else is
+  /// loosened, and only deleted once it's older than `PRUNE_EXPIRE`, since trees the depot doesn't know about might
+  /// have just started using it.
+  pub fn gc(&self, project: &ProjectName, checkouts: &HashMap<PathBuf, Vec<PathBuf>>) -> Result<(), Error> {
+    const KEEP_PREFIX: &str = "refs/pore-gc/";
+
+    // The same grace period as git gc's default gc.pruneExpire.
+    const PRUNE_EXPIRE: &str = "2.weeks.ago";
+
+    let objects_path = self.project_objects_mirror(project);
+    let dir = File::open(&objects_path).context("failed to open directory")?;
+    dir.lock_exclusive().context("failed to lock directory")?;
+
+    let objects_repo = git2::Repository::open_bare(&objects_path)
+      .with_context(|| format!("failed to open repository at {:?}", objects_path))?;
+
+    let clear_keep_refs = || -> Result<(), Error> {
+      for reference in objects_repo
+        .references_glob(&format!("{}*", KEEP_PREFIX))
+        .context("failed to list references")?
+      {
+        reference
+          .context("failed to read reference")?
+          .delete()
+          .context("failed to delete reference")?;
+      }
+      Ok(())
+    };
+
+    // Clean up after a previous gc that was interrupted.
+    clear_keep_refs()?;
+
+    let repack = || -> Result<(), Error> {
+      for remote in self.remotes()? {
+        let refs_path = self.remote_refs_mirror(&remote, project);
+        if !refs_path.exists() {
+          continue;
+        }
+
+        let refs_repo = git2::Repository::open_bare(&refs_path)
+          .with_context(|| format!("failed to open repository at {:?}", refs_path))?;
+        for reference in refs_repo.references().context("failed to list references")? {
+          let reference = reference.context("failed to read reference")?;
+          let (name, target) = match (reference.name(), reference.target()) {
+            (Some(name), Some(target)) => (name, target),
+            _ => continue,
+          };
+          let name = name.strip_prefix("refs/").unwrap_or(name);
+          objects_repo
+            .reference(
+              &format!("{}{}/{}", KEEP_PREFIX, remote, name),
+              target,
+              true,
+              "pore depot gc",
+            )
+            .with_context(|| format!("failed to keep {} from {:?}", name, refs_path))?;
+        }
+      }
+
+      let objects_dir = std::fs::canonicalize(objects_path.join("objects")).context("failed to canonicalize path")?;
+      let odb = objects_repo.odb().context("failed to open object database")?;
+      for (i, git_dir) in checkouts.get(&objects_dir).into_iter().flatten().enumerate() {
+        for oid in Depot::find_checkout_bases(&odb, git_dir)? {
+          objects_repo
+            .reference(
+              &format!("{}trees/{}/{}", KEEP_PREFIX, i, oid),
+              oid,
+              true,
+              "pore depot gc",
+            )
+            .with_context(|| format!("failed to keep {} from {:?}", oid, git_dir))?;
+        }
+      }
+
+      let git_output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&objects_path)
+        .arg("repack")
+        .arg("-A")
+        .arg("-d")
+        .arg("-q")
+        .output()
+        .context("failed to spawn git repack")?;
+      if !git_output.status.success() {
+        bail!("git repack failed: {}", String::from_utf8_lossy(&git_output.stderr));
+      }
+
+      // This still needs the refs above, since prune deletes whatever they don't reach.
+      let git_output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&objects_path)
+        .arg("prune")
+        .arg(format!("--expire={}", PRUNE_EXPIRE))
+        .output()
+        .context("failed to spawn git prune")?;
+      if !git_output.status.success() {
+        bail!("git prune failed: {}", String::from_utf8_lossy(&git_output.stderr));
+      }
+      Ok(())
+    };
+
+    let result = repack();
+    clear_keep_refs()?;
+    result?;
+
+    dir.unlock().context("failed to unlock directory")?;
+    Ok(())
+  }
+
+  /// Find the commits in an objects mirror that a checkout's HEAD and refs are based on.
+  ///
+  /// Commits made in the checkout live in its own object database, so walk back from them until we reach commits
+  /// that are in the objects mirror.
+  fn find_checkout_bases(odb: &git2::Odb, git_dir: &Path) -> Result<HashSet<git2::Oid>, Error> {
+    let repo =
+      git2::Repository::open(git_dir).with_context(|| format!("failed to open repository at {:?}", git_dir))?;
+
+    let mut pending = Vec::new();
+    if let Ok(head) = repo.head() {
+      pending.extend(head.target());
+    }
+    for reference in repo.references().context("failed to list references")? {
+      let reference = reference.context("failed to read reference")?;
+      if let Ok(commit) = reference.peel_to_commit() {
+        pending.push(commit.id());
+      }
+    }
+
+    let mut seen = HashSet::new();
+    let mut result = HashSet::new();
+    while let Some(oid) = pending.pop() {
+      if !seen.insert(oid) {
+        continue;
+      }
+
+      if odb.exists(oid) {
+        result.insert(oid);
+        continue;
+      }
+
+      // Anything the checkout can't find is already gone, there's nothing left to keep.
+      if let Ok(commit) = repo.find_commit(oid) {
+        pending.extend(commit.parent_ids());
+      }
+    }
+    Ok(result)
+  }
+
+  /// Get the path of the registry entry for a tree, named after the hash of the tree's path.
+  fn tree_registry_entry(&self, tree_root: &Path) -> Result<PathBuf, Error> {
+    let tree_root = tree_root
+      .to_str()
+      .ok_or_else(|| format_err!("invalid tree path {:?}", tree_root))?;
+    let hash =
+      git2::Oid::hash_object(git2::ObjectType::Blob, tree_root.as_bytes()).context("failed to hash tree path")?;
+    Ok(self.path.join("trees").join(hash.to_string()))
+  }
+
+  /// Record that a tree uses this depot, so that the depot knows which checkouts use its mirrors as alternates.
+  pub fn register_tree(&self, tree_root: &Path) -> Result<(), Error> {
+    let tree_root =
+      std::fs::canonicalize(tree_root).with_context(|| format!("failed to canonicalize {:?}", tree_root))?;
+    let entry_path = self.tree_registry_entry(&tree_root)?;
+    if entry_path.exists() {
+      return Ok(());
+    }
+
+    let registry_path = self.path.join("trees");
+    std::fs::create_dir_all(&registry_path)
+      .with_context(|| format!("failed to create directory {:?}", registry_path))?;
+    std::fs::write(&entry_path, format!("{}\n", tree_root.to_str().unwrap()))
+      .with_context(|| format!("failed to write {:?}", entry_path))
+  }
+
+  /// Remove a tree from the registry.
+  pub fn unregister_tree(&self, tree_root: &Path) -> Result<(), Error> {
+    let entry_path = self.tree_registry_entry(tree_root)?;
+    std::fs::remove_file(&entry_path).with_context(|| format!("failed to remove {:?}", entry_path))
+  }
+
+  /// List the trees that have been registered with this depot, whether or not they still exist.
+  pub fn registered_trees(&self) -> Result<Vec<PathBuf>, Error> {
+    let registry_path = self.path.join("trees");
+    if !registry_path.exists() {
+      return Ok(Vec::new());
+    }
+
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(&registry_path).with_context(|| format!("failed to read {:?}", registry_path))? {
+      let entry = entry.with_context(|| format!("failed to read {:?}", registry_path))?;
+      let contents =
+        std::fs::read_to_string(entry.path()).with_context(|| format!("failed to read {:?}", entry.path()))?;
+      result.push(PathBuf::from(contents.trim_end_matches('\n')));
+    }
+    result.sort();
+    Ok(result)
+  }
+
+  /// Delete the objects mirror of a project, along with its refs mirrors for every remote.
+  pub fn remove_mirrors(&self, project: &ProjectName) -> Result<(), Error> {
+    let objects_path = self.project_objects_mirror(project);
+    let dir = File::open(&objects_path).context("failed to open directory")?;
+    dir.lock_exclusive().context("failed to lock directory")?;
+
+    for remote in self.remotes()? {
+      let refs_path = self.remote_refs_mirror(&remote, project);
+      if refs_path.exists() {
+        std::fs::remove_dir_all(&refs_path).with_context(|| format!("failed to remove {:?}", refs_path))?;
+      }
+    }
+
+    std::fs::remove_dir_all(&objects_path).with_context(|| format!("failed to remove {:?}", objects_path))
+  }
+
+  /// Measure the disk usage of the objects mirror of a project, or of its refs mirror for a remote.
+  pub fn usage(&self, remote: Option<&str>, project: &ProjectName) -> Result<MirrorUsage, Error> {
+    let path = match remote {
+      Some(remote) => self.remote_refs_mirror(remote, project),
+      None => self.project_objects_mirror(project),
+    };
+
+    let mut usage = MirrorUsage {
+      path: path.clone(),
+      size: 0,
+      packs: 0,
+      loose_objects: 0,
+      last_fetch: None,
+    };
+
+    let objects_path = path.join("objects");
+    for entry in walkdir::WalkDir::new(&path) {
+      let entry = entry.with_context(|| format!("failed to walk {:?}", path))?;
+      if !entry.file_type().is_file() {
+        continue;
+      }
+
+      usage.size += entry
+        .metadata()
+        .with_context(|| format!("failed to stat {:?}", entry.path()))?
+        .len();
+
+      let parent = match entry
+        .path()
+        .parent()
+        .and_then(|parent| parent.strip_prefix(&objects_path).ok())
+      {
+        Some(parent) => parent,
+        None => continue,
+      };
+      let parent = parent.to_str().unwrap_or_default();
+      if parent == "pack" {
+        if entry.path().extension() == Some("pack".as_ref()) {
+          usage.packs += 1;
+        }
+      } else if parent.len() == 2 && parent.chars().all(|c| c.is_ascii_hexdigit()) {
+        usage.loose_objects += 1;
+      }
+    }
+
+    // Refs mirrors are only updated when their objects mirror gets fetched.
+    let fetch_head = self.project_objects_mirror(project).join("FETCH_HEAD");
+    usage.last_fetch = std::fs::metadata(fetch_head)
+      .and_then(|metadata| metadata.modified())
+      .ok();
+    Ok(usage)
+  }
+
+  /// Check the objects mirror of a project and its refs mirrors for problems.
+  pub fn fsck(&self, project: &ProjectName, connectivity: bool) -> Result<Vec<FsckProblem>, Error> {
+    let objects_path = self.project_objects_mirror(project);
+    let dir = File::open(&objects_path).context("failed to open directory")?;
+    dir.lock_shared().context("failed to lock directory")?;
+
+    let mut problems = Vec::new();
+    let objects_repo = match git2::Repository::open_bare(&objects_path) {
+      Ok(repo) => repo,
+      Err(err) => {
+        problems.push(FsckProblem::Unreadable(err.message().to_string()));
+        return Ok(problems);
+      }
+    };
+    problems.extend(Depot::find_dangling_refs(&objects_repo, None)?);
+
+    let expected_alternates = objects_path.join("objects");
+    for remote in self.remotes()? {
+      let refs_path = self.remote_refs_mirror(&remote, project);
+      if !refs_path.exists() {
+        continue;
+      }
+
+      let alternates_path = refs_path.join("objects").join("info").join("alternates");
+      let alternates = std::fs::read_to_string(&alternates_path).unwrap_or_default();
+      let alternates: Vec<&str> = alternates.lines().filter(|line| !line.is_empty()).collect();
+      if alternates != [expected_alternates.to_str().unwrap()] {
+        problems.push(FsckProblem::Alternates {
+          remote,
+          contents: alternates.join(", "),
+        });
+        continue;
+      }
+
+      match git2::Repository::open_bare(&refs_path) {
+        Ok(refs_repo) => problems.extend(Depot::find_dangling_refs(&refs_repo, Some(&remote))?),
+        Err(err) => problems.push(FsckProblem::Unreadable(format!("{}: {}", remote, err.message()))),
+      }
+    }
+
+    if connectivity {
+      let git_output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(&objects_path)
+        .arg("fsck")
+        .arg("--connectivity-only")
+        .arg("--no-dangling")
+        .arg("--no-progress")
+        .output()
+        .context("failed to spawn git fsck")?;
+      if !git_output.status.success() {
+        let mut output = String::from_utf8_lossy(&git_output.stdout).into_owned();
+        output += &String::from_utf8_lossy(&git_output.stderr);
+        problems.push(FsckProblem::Connectivity(output.trim().to_string()));
+      }
+    }
+
+    dir.unlock().context("failed to unlock directory")?;
+    Ok(problems)
+  }
+
+  fn find_dangling_refs(repo: &git2::Repository, remote: Option<&str>) -> Result<Vec<FsckProblem>, Error> {
+    let odb = repo.odb().context("failed to open object database")?;
+    let mut problems = Vec::new();
+    for reference in repo.references().context("failed to list references")? {
+      let reference = reference.context("failed to read reference")?;
+      let name = String::from_utf8_lossy(reference.name_bytes()).into_owned();
+      let resolves = match reference.resolve().ok().and_then(|r| r.target()) {
+        Some(oid) => odb.exists(oid),
+        None => false,
+      };
+      if !resolves {
+        problems.push(FsckProblem::DanglingRef {
+          remote: remote.map(str::to_string),
+          name,
+        });
+      }
+    }
+    Ok(problems)
+  }
+
+  /// Repair the problems found by `fsck`, by fixing alternates and refetching the project from its remotes.
+  pub fn repair(
+    &self,
+    remote_configs: &[&config::RemoteConfig],
+    project: &ProjectName,
+    problems: &[FsckProblem],
+  ) -> Result<(), Error> {
+    let objects_path = self.project_objects_mirror(project);
+
+    // Hold the same lock as fetch while fixing things up in place. Refetching takes the lock itself.
+    let dir = File::open(&objects_path).context("failed to open directory")?;
+    dir.lock_exclusive().context("failed to lock directory")?;
+
+    let mut refetch = false;
+    for problem in problems {
+      match problem {
+        FsckProblem::Alternates { remote, .. } => {
+          let alternates_path = self
+            .remote_refs_mirror(remote, project)
+            .join("objects")
+            .join("info")
+            .join("alternates");
+          let alternates_contents = format!("{}\n", objects_path.join("objects").to_str().unwrap());
+          std::fs::write(&alternates_path, alternates_contents)
+            .with_context(|| format!("failed to write {:?}", alternates_path))?;
+        }
+
+        FsckProblem::DanglingRef { remote: None, name } => {
+          // Drop the broken ref so that the refetch recreates it.
+          let objects_repo = git2::Repository::open_bare(&objects_path)
+            .with_context(|| format!("failed to open repository at {:?}", objects_path))?;
+          objects_repo
+            .find_reference(name)
+            .and_then(|mut reference| reference.delete())
+            .with_context(|| format!("failed to delete {}", name))?;
+          refetch = true;
+        }
+
+        FsckProblem::Unreadable(_) => bail!("can't repair unreadable repository"),
+
+        // The refs mirror's refs are replaced with the objects mirror's after fetching.
+        FsckProblem::DanglingRef { remote: Some(_), .. } | FsckProblem::Connectivity(_) => refetch = true,
+      }
+    }
+
+    dir.unlock().context("failed to unlock directory")?;
+    if !refetch {
+      return Ok(());
+    }
+
+    let objects_repo = git2::Repository::open_bare(&objects_path)
+      .with_context(|| format!("failed to open repository at {:?}", objects_path))?;
+    let mut refetched = false;
+    for remote_config in remote_configs {
+      // Recover the project's name on the remote, before renames, from the URL that it was fetched from.
+      let remote = match objects_repo.find_remote(&remote_config.name) {
+        Ok(remote) => remote,
+        Err(_) => continue,
+      };
+      let project_name = remote
+        .url()
+        .and_then(|url| url.strip_prefix(remote_config.url.as_str()))
+        .and_then(|url| url.strip_suffix(".git"));
+      if let Some(project_name) = project_name {
+        self.refetch_repo(remote_config, project_name)?;
+        refetched = true;
+      }
+    }
+
+    ensure!(refetched, "failed to find a remote to refetch {} from", project);
+    Ok(())
+  }
+
   pub fn clone_repo<T: AsRef<Path>>(
     &self,
     remote_config: &config::RemoteConfig,
     project: &str,
+    push_url: Option<&str>,
+    sparse_checkout: Option<&[String]>,
     branch: &str,
     path: T,
   ) -> Result<(), Error> {
@@ -295,15 +886,51 @@ impl Depot {
       )
       .context("failed to create remote")?;
 
-    // TODO: The push URL should be based on the review element of the manifest.
     // Projects may fetch from one remote but push to another.
+    let push_url = match push_url {
+      Some(push_url) => push_url.to_string(),
+      None => format!("{}{}", remote_config.url, project),
+    };
     repo
-      .remote_set_pushurl(&remote_config.name, Some(&format!("{}{}", remote_config.url, project)))
+      .remote_set_pushurl(&remote_config.name, Some(&push_url))
       .context("failed to set remote pushurl")?;
 
     self.update_remote_refs(remote_config, project, path)?;
 
     let head = util::parse_revision(&repo, &remote_config.name, branch)?;
+    let partial = self.is_partial(remote_config, &local_project);
+    if partial {
+      // The mirror is missing file contents, so the checkout needs to be able to fetch them from upstream itself.
+      // The refs mirror can't serve as the promisor, because it's missing them too.
+      let mut config = repo.config().context("failed to get repository config")?;
+      config
+        .set_str(
+          &format!("remote.{}.url", PROMISOR_REMOTE),
+          &format!("{}{}.git", remote_config.url, project),
+        )
+        .context("failed to create promisor remote")?;
+      Depot::set_promisor(&mut config, PROMISOR_REMOTE).context("failed to configure partial clone")?;
+    }
+
+    util::set_sparse_checkout(&repo, sparse_checkout, false).context("failed to set up sparse checkout")?;
+
+    if partial || sparse_checkout.is_some() {
+      // libgit2 can neither fetch missing objects nor respect sparse checkout, so let git do the checkout.
+      let git_output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("checkout")
+        .arg("-q")
+        .arg("--detach")
+        .arg(head.id().to_string())
+        .output()
+        .context("failed to spawn git checkout")?;
+      if !git_output.status.success() {
+        bail!("git checkout failed: {}", String::from_utf8_lossy(&git_output.stderr));
+      }
+      return Ok(());
+    }
+
     repo
       .checkout_tree(&head, None)
       .with_context(|| format!("failed to checkout HEAD at {:?}", repo.path()))?;
@@ -332,6 +959,52 @@ impl Depot {
 
     let mirror_tags = mirror_path.join("refs").join("tags");
     let repo_tags = repo_path.join("refs").join("tags");
-    Depot::replace_dir(&mirror_tags, &repo_tags).context("failed to replace tags")
+    Depot::replace_dir(&mirror_tags, &repo_tags).context("failed to replace tags")?;
+
+    Depot::replace_shallow(&mirror_path, &repo_path).context("failed to replace shallow")
+  }
+}
+
+/// The disk usage of a mirror in a depot.
+pub struct MirrorUsage {
+  pub path: PathBuf,
+
+  /// Total size of the mirror, in bytes.
+  pub size: u64,
+
+  pub packs: usize,
+  pub loose_objects: usize,
+  pub last_fetch: Option<std::time::SystemTime>,
+}
+
+/// A problem found while checking a depot.
+pub enum FsckProblem {
+  /// A repository couldn't be opened at all.
+  Unreadable(String),
+
+  /// A refs mirror's alternates don't point at the project's objects mirror.
+  Alternates { remote: String, contents: String },
+
+  /// A ref points at an object that doesn't exist, either in the objects mirror or in the refs mirror for a remote.
+  DanglingRef { remote: Option<String>, name: String },
+
+  /// `git fsck` found objects that are missing from history.
+  Connectivity(String),
+}
+
+impl fmt::Display for FsckProblem {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FsckProblem::Unreadable(err) => write!(f, "failed to open repository: {}", err),
+      FsckProblem::Alternates { remote, contents } => {
+        write!(f, "refs mirror for {} has wrong alternates: {:?}", remote, contents)
+      }
+      FsckProblem::DanglingRef { remote: None, name } => write!(f, "{} doesn't resolve", name),
+      FsckProblem::DanglingRef {
+        remote: Some(remote),
+        name,
+      } => write!(f, "{} in refs mirror for {} doesn't resolve", name, remote),
+      FsckProblem::Connectivity(output) => write!(f, "connectivity check failed: {}", output),
+    }
   }
 }
@@ -0,0 +1,393 @@
+//! A port of the Change-Id logic in Gerrit's commit-msg hook (as of Gerrit 2.14.6).
+//!
+//! This needs to produce exactly the same output as Gerrit's shell hook, so it deliberately mirrors the sed and awk
+//! scripts there, quirks included.
+
+use std::path::Path;
+
+use anyhow::{Context, Error};
+
+/// Footers that go before the Change-Id, if the message already has a footer.
+const CHANGE_ID_AFTER: &[&str] = &["bug", "depends-on", "issue", "test", "feature", "fixes", "fixed"];
+
+/// Check whether a commit message has a Change-Id, like `grep -i '^Change-Id:'`.
+pub fn has_change_id(message: &str) -> bool {
+  message.split('\n').any(|line| {
+    line
+      .get(..10)
+      .map_or(false, |prefix| prefix.eq_ignore_ascii_case("change-id:"))
+  })
+}
+
+/// Strip everything that doesn't contribute to the Change-Id: comments, Signed-off-by lines, and diffs appended by
+/// `git commit -v`. The result is normalized like `git stripspace`, without a trailing newline.
+fn clean_message(message: &str) -> String {
+  let mut lines = Vec::new();
+  for line in message.split('\n') {
+    if line.starts_with("diff --git ") {
+      lines.push("");
+      break;
+    }
+
+    if line.starts_with("Signed-off-by:") || line.starts_with('#') {
+      continue;
+    }
+
+    lines.push(line.trim_end());
+  }
+
+  let mut result: Vec<&str> = Vec::new();
+  let mut blank = false;
+  for line in lines {
+    if line.is_empty() {
+      blank = true;
+      continue;
+    }
+
+    if blank && !result.is_empty() {
+      result.push("");
+    }
+    blank = false;
+    result.push(line);
+  }
+  result.join("\n")
+}
+
+/// Format a signature like `git var GIT_AUTHOR_IDENT`.
+pub fn format_ident(signature: &git2::Signature) -> String {
+  let time = signature.when();
+  let offset = time.offset_minutes().abs();
+  format!(
+    "{} <{}> {} {}{:02}{:02}",
+    String::from_utf8_lossy(signature.name_bytes()),
+    String::from_utf8_lossy(signature.email_bytes()),
+    time.seconds(),
+    time.sign(),
+    offset / 60,
+    offset % 60
+  )
+}
+
+/// Generate a Change-Id by hashing a commit object built from the commit's metadata and cleaned message.
+fn generate_change_id(
+  tree: git2::Oid,
+  parent: Option<git2::Oid>,
+  author: &str,
+  committer: &str,
+  clean_message: &str,
+) -> Result<String, Error> {
+  let mut input = format!("tree {}\n", tree);
+  if let Some(parent) = parent {
+    input += &format!("parent {}\n", parent);
+  }
+  input += &format!("author {}\n", author);
+  input += &format!("committer {}\n", committer);
+  input += "\n";
+  input += clean_message;
+
+  let oid = git2::Oid::hash_object(git2::ObjectType::Commit, input.as_bytes()).context("failed to hash Change-Id")?;
+  Ok(format!("I{}", oid))
+}
+
+/// Length of a leading `[a-zA-Z0-9-]+`.
+fn token_len(line: &str) -> usize {
+  line
+    .bytes()
+    .take_while(|b| b.is_ascii_alphanumeric() || *b == b'-')
+    .count()
+}
+
+/// `^\[[a-zA-Z0-9-]+:`
+fn is_footer_comment_start(line: &str) -> bool {
+  match line.strip_prefix('[') {
+    Some(rest) => {
+      let len = token_len(rest);
+      len > 0 && rest[len..].starts_with(':')
+    }
+    None => false,
+  }
+}
+
+/// `^\[?[a-zA-Z0-9-]+:` and not `^[a-zA-Z0-9-]+:\/\/`
+fn is_footer_line(line: &str) -> bool {
+  let len = token_len(line);
+  if len > 0 && line[len..].starts_with("://") {
+    return false;
+  }
+
+  let rest = line.strip_prefix('[').unwrap_or(line);
+  let len = token_len(rest);
+  len > 0 && rest[len..].starts_with(':')
+}
+
+/// Splice a Change-Id into a commit message, at the start of the footer.
+fn insert_change_id(message: &str, comment_char: &str, change_id: &str) -> String {
+  let mut records: Vec<&str> = message.split('\n').collect();
+  if message.ends_with('\n') {
+    records.pop();
+  }
+
+  let mut output = String::new();
+  let mut print = |line: &str| {
+    output += line;
+    output += "\n";
+  };
+
+  // Parse the commit message as (textLine+ blankLine*)*, assuming that each textLine+ block is the footer until
+  // proven otherwise. The first block is the title, so it's never the footer.
+  let mut is_footer = false;
+  let mut footer_comment = 0;
+  let mut blank_lines = 0;
+  let mut lines = String::new();
+  for line in records {
+    if !comment_char.is_empty() && line.starts_with(comment_char) {
+      continue;
+    }
+
+    // Everything after a diff is patch data.
+    if line.starts_with("diff --git ") {
+      blank_lines = 0;
+      break;
+    }
+
+    if line.is_empty() && footer_comment == 0 {
+      blank_lines += 1;
+      continue;
+    }
+
+    if is_footer && is_footer_comment_start(line) {
+      footer_comment = 1;
+    }
+
+    if footer_comment == 1 && line.ends_with(']') {
+      footer_comment = 2;
+    }
+
+    // A non-blank line after blank lines means that the previous block wasn't the footer.
+    if blank_lines > 0 {
+      print(&lines);
+      for _ in 0..blank_lines {
+        print("");
+      }
+
+      lines.clear();
+      blank_lines = 0;
+      is_footer = true;
+      footer_comment = 0;
+    }
+
+    if footer_comment == 0 && !is_footer_line(line) {
+      is_footer = false;
+    }
+
+    if footer_comment == 2 {
+      footer_comment = 0;
+    }
+
+    if !lines.is_empty() {
+      lines += "\n";
+    }
+    lines += line;
+  }
+
+  if !is_footer {
+    print(&format!("{}\n", lines));
+    lines.clear();
+  }
+
+  // Footers listed in CHANGE_ID_AFTER come first, then the Change-Id, then everything else.
+  let change_id_line = format!("Change-Id: {}", change_id);
+  let mut unprinted = true;
+  if !lines.is_empty() {
+    for line in lines.split('\n') {
+      let lowercase = line.to_lowercase();
+      let after = CHANGE_ID_AFTER
+        .iter()
+        .any(|key| lowercase.starts_with(key) && lowercase[key.len()..].starts_with(':'));
+      if unprinted && !after {
+        unprinted = false;
+        print(&change_id_line);
+      }
+      print(line);
+    }
+  }
+
+  if unprinted {
+    print(&change_id_line);
+  }
+
+  output
+}
+
+/// Add a Change-Id to a commit message if it needs one, like Gerrit's commit-msg hook.
+///
+/// Returns None if the message should be left as is: if it already has a Change-Id, if it's empty, if it's a fixup
+/// or squash commit, or if gerrit.createChangeId is disabled.
+pub fn add_change_id(
+  config: &git2::Config,
+  message: &str,
+  tree: git2::Oid,
+  parent: Option<git2::Oid>,
+  author: &str,
+  committer: &str,
+) -> Result<Option<String>, Error> {
+  let clean_message = clean_message(message);
+  if clean_message.is_empty() {
+    return Ok(None);
+  }
+
+  // Don't add Change-Ids to temporary commits.
+  let title = clean_message.split('\n').next().unwrap_or("");
+  if title.starts_with("fixup!") || title.starts_with("squash!") {
+    return Ok(None);
+  }
+
+  if let Ok(false) = config.get_bool("gerrit.createChangeId") {
+    return Ok(None);
+  }
+
+  if has_change_id(message) {
+    return Ok(None);
+  }
+
+  let change_id = generate_change_id(tree, parent, author, committer, &clean_message)?;
+  let comment_char = config.get_string("core.commentChar").unwrap_or_else(|_| "#".into());
+  Ok(Some(insert_change_id(message, &comment_char, &change_id)))
+}
+
+fn git_var(args: &[&str]) -> Result<String, Error> {
+  let output = std::process::Command::new("git")
+    .args(args)
+    .output()
+    .with_context(|| format!("failed to spawn git {}", args.join(" ")))?;
+  ensure!(output.status.success(), "git {} failed", args.join(" "));
+  Ok(String::from_utf8_lossy(&output.stdout).trim_end().to_string())
+}
+
+/// Entry point for the commit-msg hook: add a Change-Id to the commit message being written in `path`.
+pub fn run(path: &Path) -> Result<(), Error> {
+  let repo = git2::Repository::open_from_env().context("failed to open repository")?;
+  let config = repo.config().context("failed to read git config")?;
+  let message = std::fs::read(path).with_context(|| format!("failed to read {:?}", path))?;
+  let message = String::from_utf8_lossy(&message);
+
+  // Ask git rather than libgit2 for these, so that they respect the environment git sets up for hooks, e.g.
+  // GIT_INDEX_FILE and GIT_AUTHOR_DATE.
+  let tree = git2::Oid::from_str(&git_var(&["write-tree"])?).context("failed to parse tree")?;
+  let parent = repo
+    .head()
+    .ok()
+    .and_then(|head| head.peel_to_commit().ok())
+    .map(|commit| commit.id());
+  let author = git_var(&["var", "GIT_AUTHOR_IDENT"])?;
+  let committer = git_var(&["var", "GIT_COMMITTER_IDENT"])?;
+
+  if let Some(message) = add_change_id(&config, &message, tree, parent, &author, &committer)? {
+    std::fs::write(path, message).with_context(|| format!("failed to write {:?}", path))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Expected results are from Gerrit 2.14.6's commit-msg hook, run with these identities.
+  const AUTHOR: &str = "A U Thor <author@example.com> 1112911993 -0700";
+  const COMMITTER: &str = "C O Mitter <committer@example.com> 1112912053 -0700";
+  const TREE: &str = "3683f870be446c7cc05ffaef9fa06415276e1828";
+  const PARENT: &str = "b77d05b6fee3812fd78c8cc09a4e5134ce507885";
+
+  fn hook(message: &str, comment_char: &str, tree: &str, parent: Option<&str>) -> String {
+    let tree = git2::Oid::from_str(tree).unwrap();
+    let parent = parent.map(|parent| git2::Oid::from_str(parent).unwrap());
+    let change_id = generate_change_id(tree, parent, AUTHOR, COMMITTER, &clean_message(message)).unwrap();
+    insert_change_id(message, comment_char, &change_id)
+  }
+
+  #[test]
+  fn title_only() {
+    assert_eq!(
+      hook("Add a file", "#", TREE, Some(PARENT)),
+      "Add a file\n\nChange-Id: Ia6560253ad90f20066a93640d2cc5b542ebc9f44\n"
+    );
+  }
+
+  #[test]
+  fn root_commit() {
+    assert_eq!(
+      hook(
+        "Initial commit\n",
+        "#",
+        "aaff74984cccd156a469afa7d9ab10e4777beb24",
+        None
+      ),
+      "Initial commit\n\nChange-Id: I64cbf391e05f0e85b95647c89533ad48a11cf904\n"
+    );
+  }
+
+  #[test]
+  fn footer() {
+    let message = "Fix the frobnicator\n\nIt was broken.\n\nBug: 123\nTest: m frobnicator\n\
+                   Signed-off-by: A U Thor <author@example.com>\n";
+    assert_eq!(
+      clean_message(message),
+      "Fix the frobnicator\n\nIt was broken.\n\nBug: 123\nTest: m frobnicator"
+    );
+    assert_eq!(
+      hook(message, "#", TREE, Some(PARENT)),
+      "Fix the frobnicator\n\nIt was broken.\n\nBug: 123\nTest: m frobnicator\n\
+       Change-Id: I51d302977a60fdaf58c2deb36f9d7c5802831990\n\
+       Signed-off-by: A U Thor <author@example.com>\n"
+    );
+  }
+
+  #[test]
+  fn footer_comment() {
+    assert_eq!(
+      hook(
+        "Title\n\nBody\n\nKey: value\n[comment: something\nmore]\nOther: x\n",
+        "#",
+        TREE,
+        Some(PARENT)
+      ),
+      "Title\n\nBody\n\nChange-Id: I93cfe9414fcea780d2127ef892c8ed2a47a89d47\n\
+       Key: value\n[comment: something\nmore]\nOther: x\n"
+    );
+  }
+
+  #[test]
+  fn url_is_not_footer() {
+    assert_eq!(
+      hook("Title\n\nhttp://example.com/foo\n", "#", TREE, Some(PARENT)),
+      "Title\n\nhttp://example.com/foo\n\nChange-Id: I8b937f3fe59aefb2559f748233c3622d46a874e1\n"
+    );
+  }
+
+  #[test]
+  fn diff() {
+    let message = "Title\n\nBody\n# Please enter the commit message\ndiff --git a/b b/b\n+b\n";
+    assert_eq!(clean_message(message), "Title\n\nBody");
+    assert_eq!(
+      hook(message, "#", TREE, Some(PARENT)),
+      "Title\n\nBody\n\nChange-Id: Ic39294caf05831220bcc3f1dd49621fa8768c26c\n"
+    );
+  }
+
+  #[test]
+  fn comment_char() {
+    // The hook only strips lines starting with core.commentChar when inserting the Change-Id, not when generating it.
+    let message = "Title\n; a comment\n\nBody\n";
+    assert_eq!(clean_message(message), "Title\n; a comment\n\nBody");
+    assert_eq!(
+      hook(message, ";", TREE, Some(PARENT)),
+      "Title\n\nBody\n\nChange-Id: I523b396df1b70a87ddff9f878d211b8a9a466869\n"
+    );
+  }
+
+  #[test]
+  fn existing_change_id() {
+    assert!(has_change_id("Title\n\nchange-id: I1234\n"));
+    assert!(!has_change_id("Title\n\nSee Change-Id: I1234\n"));
+  }
+}
@@ -1,4 +1,6 @@
-use std::{collections::HashMap, sync::OnceLock};
+use std::{collections::HashMap, path::Path, sync::OnceLock};
+
+pub mod commit_msg;
 
 pub fn hooks() -> &'static HashMap<&'static str, &'static str> {
   static HOOKS: OnceLock<HashMap<&'static str, &'static str>> = OnceLock::new();
@@ -10,3 +12,9 @@ pub fn hooks() -> &'static HashMap<&'static str, &'static str> {
     map
   })
 }
+
+/// Fill in the path of the pore binary in a hook, for hooks that call back into pore.
+pub fn render(contents: &str, pore: &Path) -> String {
+  let pore = pore.to_string_lossy().replace('\'', "'\\''");
+  contents.replace("@PORE@", &format!("'{}'", pore))
+}
@@ -56,9 +56,9 @@ mod update_check;
 mod util;
 
 use config::Config;
-use depot::Depot;
+use depot::{Depot, FsckProblem};
 use manifest::Manifest;
-use tree::{CheckoutType, FetchTarget, FetchType, FileState, GroupFilter, Tree};
+use tree::{CheckoutType, DownloadMode, FetchTarget, FetchType, FileState, GroupFilter, Tree};
 use update_check::UpdateChecker;
 
 fn aosp_remote_style() -> console::Style {
@@ -194,6 +194,10 @@ enum Commands {
     #[arg(short)]
     detach: bool,
 
+    /// Rebase checked out branches that can't be fast-forwarded onto the manifest revision
+    #[arg(long, conflicts_with = "detach")]
+    rebase: bool,
+
     /// Don't checkout, only update the refs
     #[arg(short, long = "refs-only")]
     refs_only: bool,
@@ -256,12 +260,12 @@ enum Commands {
     no_verify: bool,
 
     /// Comma separated list of reviewers
-    /// User names without a domain will be assumed to be @google.com
+    /// Aliases are expanded and user names without a domain get the remote's email_domain
     #[arg(long = "re", verbatim_doc_comment)]
     reviewers: Option<String>,
 
     /// Comma separated list of users to CC
-    /// User names without a domain will be assumed to be @google.com"
+    /// Aliases are expanded and user names without a domain get the remote's email_domain
     #[arg(long, verbatim_doc_comment)]
     cc: Option<String>,
 
@@ -270,13 +274,42 @@ enum Commands {
     private: bool,
 
     /// Upload as work in progress change
-    #[arg(long)]
+    #[arg(long, conflicts_with = "ready")]
     wip: bool,
 
+    /// Mark the change as ready for review, if it was work in progress
+    #[arg(long)]
+    ready: bool,
+
+    /// Upload as a change edit, instead of a new patch set
+    #[arg(long)]
+    edit: bool,
+
     /// Use local branch name as topic
     #[arg(short = 't')]
     branch_name_as_topic: bool,
 
+    /// Set the topic of the change
+    #[arg(long, conflicts_with = "branch_name_as_topic")]
+    topic: Option<String>,
+
+    /// Add hashtags to the change (comma separated, can be used multiple times)
+    #[arg(long = "hashtag", alias = "ht", value_delimiter = ',')]
+    hashtags: Vec<String>,
+
+    /// Apply a label vote to the change, e.g. Code-Review+1 (comma separated, can be used multiple times)
+    #[arg(short = 'l', long = "label", value_delimiter = ',')]
+    labels: Vec<String>,
+
+    /// Who to send email notifications to
+    #[arg(long, value_parser = ["NONE", "OWNER", "OWNER_REVIEWERS", "ALL"], ignore_case = true)]
+    notify: Option<String>,
+
+    /// Comma separated list of additional users to notify
+    /// Aliases are expanded and user names without a domain get the remote's email_domain
+    #[arg(long = "notify-to", verbatim_doc_comment)]
+    notify_to: Option<String>,
+
     /// Enable autosubmit
     #[arg(long)]
     autosubmit: bool,
@@ -297,11 +330,31 @@ enum Commands {
     #[arg(short = 'm', long = "message")]
     ps_description: Option<String>,
 
+    /// Upload to a different branch than the manifest's dest-branch
+    #[arg(short = 'D', long = "dest", alias = "destination")]
+    dest: Option<String>,
+
     /// Don't upload; just show upload commands
     #[arg(long = "dry-run")]
     dry_run: bool,
   },
 
+  /// Delete a topic branch in every project
+  Abandon {
+    /// Name of the branch to delete
+    #[arg(required_unless_present = "all")]
+    branch: Option<String>,
+
+    /// Delete all local branches
+    #[arg(long)]
+    all: bool,
+
+    /// Path(s) of the projects in which to delete branches
+    /// Defaults to all repositories in the tree
+    #[clap(verbatim_doc_comment)]
+    path: Option<Vec<PathBuf>>,
+  },
+
   /// Prune branches that have been merged
   Prune {
     /// Path(s) to prune
@@ -320,6 +373,30 @@ enum Commands {
     /// Suppress output
     #[arg(long, short)]
     quiet: bool,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+  },
+
+  /// Show changes across the entire tree
+  Diff {
+    /// Path(s) beneath which to show changes
+    /// Defaults to all repositories in the tree if unspecified
+    #[clap(verbatim_doc_comment)]
+    path: Option<Vec<PathBuf>>,
+
+    /// Show changes staged in the index instead of unstaged changes
+    #[arg(long, conflicts_with = "upstream")]
+    cached: bool,
+
+    /// Show committed changes that aren't in the manifest revision
+    #[arg(long)]
+    upstream: bool,
+
+    /// Show a diffstat instead of a patch
+    #[arg(long)]
+    stat: bool,
   },
 
   /// Run a command in each project in the tree
@@ -347,6 +424,26 @@ enum Commands {
     group_filters: Option<String>,
   },
 
+  /// Search tracked files across the entire tree
+  Grep {
+    /// Regular expression to search for
+    pattern: String,
+
+    /// Path(s) beneath which to search
+    /// Defaults to all repositories in the tree if unspecified
+    #[clap(verbatim_doc_comment)]
+    path: Option<Vec<PathBuf>>,
+
+    /// Filter projects that satisfy a comma delimited list of groups
+    /// Groups can be prepended with - to specifically exclude them
+    #[arg(short, verbatim_doc_comment)]
+    group_filters: Option<String>,
+
+    /// Ignore case when matching
+    #[arg(short, long)]
+    ignore_case: bool,
+  },
+
   /// Run repo's preupload hooks
   Preupload {
     /// Path(s) beneath which to run preupload hooks
@@ -396,6 +493,164 @@ enum Commands {
 
     path: Option<Vec<PathBuf>>,
   },
+
+  /// Download a change from Gerrit and check it out
+  Download {
+    /// Cherry-pick the change instead of checking it out
+    #[arg(short, group = "mode")]
+    cherry_pick: bool,
+
+    /// Revert the change instead of checking it out
+    #[arg(short, group = "mode")]
+    revert: bool,
+
+    /// Fast-forward to the change instead of checking it out
+    #[arg(short, group = "mode")]
+    ff_only: bool,
+
+    /// Path or name of the project
+    project: String,
+
+    /// Change to download in the format <CHANGE>[/<PATCHSET>]
+    /// PATCHSET defaults to the latest patchset if unspecified
+    #[clap(verbatim_doc_comment)]
+    change: String,
+  },
+
+  /// Show or change which files of a project are checked out
+  Sparse {
+    /// Path of the project
+    project: PathBuf,
+
+    /// Patterns of files to check out, in the format of .git/info/sparse-checkout
+    /// Shows the current patterns if unspecified
+    #[clap(verbatim_doc_comment)]
+    patterns: Vec<String>,
+
+    /// Add to the current patterns instead of replacing them
+    #[arg(short, long)]
+    add: bool,
+
+    /// Check out the entire project, even if the manifest specifies patterns
+    #[arg(short, long, conflicts_with_all = ["patterns", "add"])]
+    disable: bool,
+
+    /// Go back to the patterns specified by the manifest
+    #[arg(short, long, conflicts_with_all = ["patterns", "add", "disable"])]
+    reset: bool,
+  },
+
+  /// Maintain the depots shared between trees
+  Depot {
+    #[command(subcommand)]
+    command: DepotCommands,
+  },
+
+  /// Run a git hook installed by pore
+  #[command(hide = true)]
+  Hook {
+    #[command(subcommand)]
+    hook: HookCommands,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum HookCommands {
+  /// Add a Change-Id to a commit message
+  CommitMsg {
+    /// Path of the file containing the commit message
+    file: PathBuf,
+  },
+}
+
+#[derive(Subcommand, Debug)]
+enum DepotCommands {
+  /// Repack the objects mirrors in a depot
+  Gc {
+    /// Depot to operate on
+    /// Defaults to all configured depots if unspecified
+    #[arg(short, long, verbatim_doc_comment)]
+    depot: Option<String>,
+  },
+
+  /// Check the objects and refs mirrors in a depot for corruption
+  Fsck {
+    /// Depot to operate on
+    /// Defaults to all configured depots if unspecified
+    #[arg(short, long, verbatim_doc_comment)]
+    depot: Option<String>,
+
+    /// Also check that the history of every ref is complete (slow)
+    #[arg(short, long)]
+    connectivity: bool,
+
+    /// Repair problems by refetching the affected projects
+    #[arg(short, long)]
+    repair: bool,
+  },
+
+  /// List the mirrors in a depot, with their disk usage
+  #[command(visible_alias = "du")]
+  List {
+    /// Depot to operate on
+    /// Defaults to all configured depots if unspecified
+    #[arg(short, long, verbatim_doc_comment)]
+    depot: Option<String>,
+
+    /// Sort order, largest or most recent first for everything except name
+    #[arg(short, long, value_enum, default_value_t = DepotSort::Name)]
+    sort: DepotSort,
+
+    /// Output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+  },
+
+  /// Delete mirrors that aren't used by any tree
+  Prune {
+    /// Depot to operate on
+    /// Defaults to all configured depots if unspecified
+    #[arg(short, long, verbatim_doc_comment)]
+    depot: Option<String>,
+
+    /// Directories to search for trees, in addition to the trees registered with the depot
+    #[arg(short, long)]
+    scan: Vec<PathBuf>,
+
+    /// Don't ask for confirmation before deleting
+    #[arg(short, long)]
+    yes: bool,
+
+    /// Trees to consider, in addition to the trees registered with the depot
+    trees: Vec<PathBuf>,
+  },
+
+  /// List the trees that use a depot
+  Trees {
+    /// Depot to operate on
+    /// Defaults to all configured depots if unspecified
+    #[arg(short, long, verbatim_doc_comment)]
+    depot: Option<String>,
+
+    /// Forget about trees that no longer exist
+    #[arg(short, long)]
+    prune: bool,
+  },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum DepotSort {
+  Name,
+  Size,
+  Packs,
+  Loose,
+  Fetched,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+enum OutputFormat {
+  Text,
+  Json,
 }
 
 impl std::fmt::Display for Commands {
@@ -410,9 +665,12 @@ impl std::fmt::Display for Commands {
       Commands::Start { .. } => write!(f, "start"),
       Commands::Rebase { .. } => write!(f, "rebase"),
       Commands::Upload { .. } => write!(f, "upload"),
+      Commands::Abandon { .. } => write!(f, "abandon"),
       Commands::Prune { .. } => write!(f, "prune"),
       Commands::Status { .. } => write!(f, "status"),
+      Commands::Diff { .. } => write!(f, "diff"),
       Commands::Forall { .. } => write!(f, "forall"),
+      Commands::Grep { .. } => write!(f, "grep"),
       Commands::Preupload { .. } => write!(f, "preupload"),
       Commands::Import { .. } => write!(f, "import"),
       Commands::List { .. } => write!(f, "list"),
@@ -420,6 +678,10 @@ impl std::fmt::Display for Commands {
       Commands::Manifest { .. } => write!(f, "manifest"),
       Commands::Config { .. } => write!(f, "config"),
       Commands::Info { .. } => write!(f, "info"),
+      Commands::Download { .. } => write!(f, "download"),
+      Commands::Sparse { .. } => write!(f, "sparse"),
+      Commands::Depot { .. } => write!(f, "depot"),
+      Commands::Hook { .. } => write!(f, "hook"),
     }
   }
 }
@@ -521,8 +783,7 @@ fn cmd_clone(
 
   let group_filters = group_filters.map(parse_group_filters).unwrap_or_default();
 
-  // TODO: Add locking?
-  let mut tree = Tree::construct(
+  let (mut tree, _lock) = Tree::construct(
     &depot,
     &tree_root,
     manifest_config,
@@ -549,6 +810,7 @@ fn cmd_clone(
     false,
     false,
     false,
+    false,
   )
 }
 
@@ -636,12 +898,25 @@ impl TreeStatusDisplayData {
   }
 }
 
+#[derive(Serialize)]
+struct StatusErrorJson {
+  project: String,
+  error: String,
+}
+
+#[derive(Serialize)]
+struct TreeStatusJson<'a> {
+  projects: Vec<&'a tree::ProjectStatus>,
+  errors: Vec<StatusErrorJson>,
+}
+
 fn cmd_status(
   config: &Config,
   pool: &mut Pool,
   tree: &Tree,
   status_under: Option<Vec<PathBuf>>,
   quiet: bool,
+  format: OutputFormat,
 ) -> Result<i32, Error> {
   pool.quiet(quiet);
 
@@ -650,6 +925,30 @@ fn cmd_status(
 
   let column_padding = 4;
   let display_data = TreeStatusDisplayData::from_results(results.successful.iter().map(|r| &r.result).collect());
+
+  if format == OutputFormat::Json {
+    let dirty = display_data.projects.iter().filter(|project| project.dirty).count();
+
+    let document = TreeStatusJson {
+      projects: results.successful.iter().map(|r| &r.result).collect(),
+      errors: results
+        .failed
+        .iter()
+        .map(|error| StatusErrorJson {
+          project: error.name.clone(),
+          error: format!("{:#}", error.result),
+        })
+        .collect(),
+    };
+    serde_json::to_writer_pretty(std::io::stdout(), &document).context("failed to write status")?;
+    println!();
+
+    if !results.failed.is_empty() {
+      bail!("failed to git status");
+    }
+
+    return Ok(dirty.try_into().unwrap());
+  }
   for project in display_data.projects {
     if project.dirty {
       status += 1;
@@ -788,6 +1087,475 @@ fn cmd_import(config: &Config, pool: &mut Pool, target_path: Option<PathBuf>, co
   Ok(0)
 }
 
+fn find_depots(config: &Config, depot: Option<&str>) -> Result<Vec<Depot>, Error> {
+  match depot {
+    Some(depot) => Ok(vec![config.find_depot(depot)?]),
+    None => config.depots.keys().map(|depot| config.find_depot(depot)).collect(),
+  }
+}
+
+fn cmd_depot_gc(pool: &mut Pool, depots: &[Depot]) -> Result<i32, Error> {
+  // Checkouts can have commits based on objects that are no longer reachable from the mirrors' refs.
+  let mut checkouts: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+  for depot in depots {
+    for tree_root in depot.registered_trees()? {
+      if !tree_root.join(".pore").exists() {
+        continue;
+      }
+
+      let tree = Tree::from_path(&tree_root)?;
+      for (alternate, git_dirs) in tree.checkouts_by_alternate()? {
+        checkouts.entry(alternate).or_default().extend(git_dirs);
+      }
+    }
+  }
+
+  let mut job = Job::with_name("gc");
+  for depot in depots {
+    for project in depot.projects()? {
+      let depot = depot.clone();
+      let checkouts = &checkouts;
+      job.add_task(format!("{}: {}", depot.name, project), move || {
+        depot.gc(&project, checkouts)
+      });
+    }
+  }
+
+  let result = pool.execute(job);
+  if !result.failed.is_empty() {
+    for failure in result.failed {
+      eprintln!("{}: {:?}", failure.name, failure.result);
+    }
+    bail!("failed to gc");
+  }
+
+  Ok(0)
+}
+
+fn cmd_depot_fsck(
+  config: &Config,
+  pool: &mut Pool,
+  depots: &[Depot],
+  connectivity: bool,
+  repair: bool,
+) -> Result<i32, Error> {
+  let mut job = Job::with_name("fsck");
+  for depot in depots {
+    let remote_configs: Vec<&config::RemoteConfig> = config
+      .remotes
+      .iter()
+      .filter(|remote| remote.depot == depot.name)
+      .collect();
+    for project in depot.projects()? {
+      let remote_configs = remote_configs.clone();
+      job.add_task(
+        format!("{}: {}", depot.name, project),
+        move || -> Result<(Vec<FsckProblem>, bool), Error> {
+          let problems = depot.fsck(&project, connectivity)?;
+          if !repair || problems.is_empty() {
+            return Ok((problems, false));
+          }
+
+          depot.repair(&remote_configs, &project, &problems)?;
+          let repaired = depot.fsck(&project, connectivity)?.is_empty();
+          Ok((problems, repaired))
+        },
+      );
+    }
+  }
+
+  let mut results = pool.execute(job);
+  results.successful.sort_by(|a, b| a.name.cmp(&b.name));
+
+  let mut broken = 0;
+  for result in &results.successful {
+    let (problems, repaired) = &result.result;
+    if problems.is_empty() {
+      continue;
+    }
+
+    println!("{}", project_style().apply_to(&result.name));
+    for problem in problems {
+      println!("  {}", console::style(problem).red());
+    }
+
+    if *repaired {
+      println!("  {}", console::style("repaired").green());
+    } else {
+      broken += 1;
+    }
+  }
+
+  for failure in &results.failed {
+    eprintln!("{}: {:?}", failure.name, failure.result);
+  }
+
+  if broken + results.failed.len() == 0 {
+    Ok(0)
+  } else {
+    Ok(1)
+  }
+}
+
+#[derive(Serialize)]
+struct DepotListEntry {
+  depot: String,
+
+  /// The remote of a refs mirror, or None for an objects mirror.
+  remote: Option<String>,
+
+  project: String,
+  path: PathBuf,
+  size: u64,
+  packs: usize,
+  loose_objects: usize,
+
+  /// Time of the last fetch, serialized as an RFC 3339 timestamp.
+  #[serde(serialize_with = "serialize_rfc3339")]
+  last_fetch: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+fn serialize_rfc3339<S: serde::Serializer>(
+  time: &Option<chrono::DateTime<chrono::Utc>>,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  match time {
+    Some(time) => serializer.serialize_some(&time.to_rfc3339()),
+    None => serializer.serialize_none(),
+  }
+}
+
+fn format_size(bytes: u64) -> String {
+  const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let mut size = bytes as f64;
+  let mut unit = 0;
+  while size >= 1024.0 && unit + 1 < UNITS.len() {
+    size /= 1024.0;
+    unit += 1;
+  }
+
+  if unit == 0 {
+    format!("{} {}", bytes, UNITS[unit])
+  } else {
+    format!("{:.1} {}", size, UNITS[unit])
+  }
+}
+
+fn cmd_depot_list(pool: &mut Pool, depots: &[Depot], sort: DepotSort, format: OutputFormat) -> Result<i32, Error> {
+  let mut job = Job::with_name("du");
+  for depot in depots {
+    let mut mirrors: Vec<(Option<String>, depot::ProjectName)> =
+      depot.projects()?.into_iter().map(|project| (None, project)).collect();
+    for remote in depot.remotes()? {
+      for project in depot.remote_projects(&remote)? {
+        mirrors.push((Some(remote.clone()), project));
+      }
+    }
+
+    for (remote, project) in mirrors {
+      let name = match &remote {
+        Some(remote) => format!("{}: refs/{}/{}", depot.name, remote, project),
+        None => format!("{}: objects/{}", depot.name, project),
+      };
+      job.add_task(name, move || -> Result<DepotListEntry, Error> {
+        let usage = depot.usage(remote.as_deref(), &project)?;
+        Ok(DepotListEntry {
+          depot: depot.name.clone(),
+          remote,
+          project: project.to_string(),
+          path: usage.path,
+          size: usage.size,
+          packs: usage.packs,
+          loose_objects: usage.loose_objects,
+          last_fetch: usage.last_fetch.map(chrono::DateTime::<chrono::Utc>::from),
+        })
+      });
+    }
+  }
+
+  let results = pool.execute(job);
+  for failure in &results.failed {
+    eprintln!("{}: {:?}", failure.name, failure.result);
+  }
+
+  let mut entries: Vec<DepotListEntry> = results.successful.into_iter().map(|result| result.result).collect();
+  entries.sort_by(|a, b| (&a.depot, &a.project, &a.remote).cmp(&(&b.depot, &b.project, &b.remote)));
+  match sort {
+    DepotSort::Name => {}
+    DepotSort::Size => entries.sort_by(|a, b| b.size.cmp(&a.size)),
+    DepotSort::Packs => entries.sort_by(|a, b| b.packs.cmp(&a.packs)),
+    DepotSort::Loose => entries.sort_by(|a, b| b.loose_objects.cmp(&a.loose_objects)),
+    DepotSort::Fetched => entries.sort_by(|a, b| b.last_fetch.cmp(&a.last_fetch)),
+  }
+
+  if format == OutputFormat::Json {
+    serde_json::to_writer_pretty(std::io::stdout(), &entries).context("failed to write JSON")?;
+    println!();
+  } else {
+    println!(
+      "{:>10}  {:>5}  {:>7}  {:16}  MIRROR",
+      "SIZE", "PACKS", "LOOSE", "LAST FETCH"
+    );
+    for entry in &entries {
+      let last_fetch = match &entry.last_fetch {
+        Some(last_fetch) => last_fetch
+          .with_timezone(&chrono::Local)
+          .format("%Y-%m-%d %H:%M")
+          .to_string(),
+        None => "never".to_string(),
+      };
+      let mirror = match &entry.remote {
+        Some(remote) => format!("refs/{}/{}.git", remote, entry.project),
+        None => format!("objects/{}.git", entry.project),
+      };
+      println!(
+        "{:>10}  {:>5}  {:>7}  {:16}  {}{}",
+        format_size(entry.size),
+        entry.packs,
+        entry.loose_objects,
+        last_fetch,
+        if depots.len() > 1 {
+          format!("{}: ", entry.depot)
+        } else {
+          String::new()
+        },
+        project_style().apply_to(mirror)
+      );
+    }
+
+    let total: u64 = entries.iter().map(|entry| entry.size).sum();
+    println!("{:>10}  total", format_size(total));
+  }
+
+  if results.failed.is_empty() {
+    Ok(0)
+  } else {
+    Ok(1)
+  }
+}
+
+fn cmd_depot_trees(depots: &[Depot], prune: bool) -> Result<i32, Error> {
+  for depot in depots {
+    if depots.len() > 1 {
+      println!("{}", project_style().apply_to(format!("depot {}", depot.name)));
+    }
+
+    for tree_root in depot.registered_trees()? {
+      match Tree::from_path(&tree_root) {
+        Ok(tree) => {
+          println!(
+            "{}  {}  {} {}",
+            console::style("live ").green(),
+            tree_root.display(),
+            tree.config.manifest,
+            branch_style().apply_to(&tree.config.branch)
+          );
+        }
+        // Only forget about trees that are actually gone, since a tree that we can't read might still be using the
+        // depot's mirrors.
+        Err(err) if !is_missing(&tree_root.join(".pore")) => {
+          eprintln!("warning: failed to read tree {}: {:#}", tree_root.display(), err);
+          println!("{}  {}", console::style("error").yellow(), tree_root.display());
+        }
+        Err(_) => {
+          let removed = if prune {
+            depot.unregister_tree(&tree_root)?;
+            " (removed)"
+          } else {
+            ""
+          };
+          println!("{}  {}{}", console::style("stale").red(), tree_root.display(), removed);
+        }
+      }
+    }
+  }
+
+  Ok(0)
+}
+
+/// Check whether a path definitely doesn't exist, as opposed to being inaccessible.
+fn is_missing(path: &Path) -> bool {
+  match std::fs::symlink_metadata(path) {
+    Ok(_) => false,
+    Err(err) => err.kind() == std::io::ErrorKind::NotFound,
+  }
+}
+
+/// Find the roots of all of the trees beneath a directory.
+fn scan_for_trees(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+  let mut result = Vec::new();
+  let mut walker = walkdir::WalkDir::new(directory).into_iter();
+  while let Some(entry) = walker.next() {
+    let entry = entry.with_context(|| format!("failed to walk {:?}", directory))?;
+    if !entry.file_type().is_dir() {
+      continue;
+    }
+
+    if entry.path().join(".pore").join("tree.toml").exists() {
+      result.push(entry.path().to_path_buf());
+      walker.skip_current_dir();
+    } else if entry.file_name() == ".git" || entry.file_name() == ".repo" {
+      walker.skip_current_dir();
+    }
+  }
+  Ok(result)
+}
+
+fn cmd_depot_prune(
+  config: &Config,
+  depots: &[Depot],
+  trees: Vec<PathBuf>,
+  scan: Vec<PathBuf>,
+  yes: bool,
+) -> Result<i32, Error> {
+  let registered_trees = || -> Result<HashSet<PathBuf>, Error> {
+    let mut result = HashSet::new();
+    for depot in config.depots.keys() {
+      result.extend(config.find_depot(depot)?.registered_trees()?);
+    }
+    Ok(result)
+  };
+
+  let mut tree_roots: Vec<PathBuf> = trees;
+  for directory in scan {
+    tree_roots.extend(scan_for_trees(&directory)?);
+  }
+
+  // Registered trees that no longer exist don't reference anything, but every other tree we know about does.
+  let registered = registered_trees()?;
+  tree_roots.extend(
+    registered
+      .iter()
+      .filter(|tree_root| tree_root.join(".pore").exists())
+      .cloned(),
+  );
+
+  let mut seen = HashSet::new();
+  let mut referenced: HashSet<(String, String)> = HashSet::new();
+  let mut alternates = HashSet::new();
+  let mut locks = Vec::new();
+  for tree_root in tree_roots {
+    let tree_root =
+      std::fs::canonicalize(&tree_root).with_context(|| format!("failed to find tree {:?}", tree_root))?;
+    if !seen.insert(tree_root.clone()) {
+      continue;
+    }
+
+    // If we can't figure out what a tree uses, we can't safely delete anything.
+    let tree = Tree::from_path(&tree_root)?;
+
+    // Trees that haven't been used since before depots kept track of them can only be found explicitly.
+    if !registered.contains(&tree_root) {
+      eprintln!(
+        "warning: tree {} wasn't registered with its depot, registering it",
+        tree_root.display()
+      );
+      tree.register(config)?;
+    }
+
+    // Keep syncs from starting to use mirrors that we're about to decide are unused.
+    locks.push(tree.lock()?);
+    let manifest = tree
+      .read_manifest()
+      .with_context(|| format!("failed to read manifest for {:?}", tree_root))?;
+    let projects = tree.collect_manifest_projects(config, &manifest, None, None)?;
+
+    let manifest_remote = config.find_remote(&tree.config.remote)?;
+    referenced.insert((
+      manifest_remote.depot.clone(),
+      Depot::apply_project_renames(manifest_remote, &tree.config.manifest).to_string(),
+    ));
+    for project in &projects {
+      let remote_config = config.find_remote(&project.remote)?;
+      referenced.insert((
+        remote_config.depot.clone(),
+        Depot::apply_project_renames(remote_config, &project.project_name).to_string(),
+      ));
+    }
+
+    alternates.extend(tree.alternates()?);
+    println!("Using tree {}", tree_root.display());
+  }
+
+  let mut unreferenced = Vec::new();
+  for depot in depots {
+    for project in depot.projects()? {
+      if referenced.contains(&(depot.name.clone(), project.to_string())) {
+        continue;
+      }
+
+      let usage = depot.usage(None, &project)?;
+      let objects_dir = std::fs::canonicalize(usage.path.join("objects"))?;
+      if alternates.contains(&objects_dir) {
+        eprintln!(
+          "warning: {}: objects/{} is still used as an alternate, skipping",
+          depot.name, project
+        );
+        continue;
+      }
+
+      let mut size = usage.size;
+      for remote in depot.remotes()? {
+        if depot.remote_projects(&remote)?.contains(&project) {
+          size += depot.usage(Some(&remote), &project)?.size;
+        }
+      }
+      unreferenced.push((depot, project, size));
+    }
+  }
+
+  if unreferenced.is_empty() {
+    println!("No unused mirrors found");
+    return Ok(0);
+  }
+
+  for (depot, project, size) in &unreferenced {
+    println!(
+      "{:>10}  {}: {}",
+      format_size(*size),
+      depot.name,
+      project_style().apply_to(project)
+    );
+  }
+
+  let total: u64 = unreferenced.iter().map(|(_, _, size)| size).sum();
+  if !yes {
+    print!(
+      "Delete {} unused mirror{} ({})? [y/N]? ",
+      unreferenced.len(),
+      if unreferenced.len() == 1 { "" } else { "s" },
+      format_size(total)
+    );
+    std::io::stdout().flush()?;
+
+    let line = util::read_line()?;
+    if line != "y" && line != "Y" {
+      bail!("prune aborted by user");
+    }
+  }
+
+  // A tree that was cloned since we looked might use any of these.
+  let new_trees: Vec<String> = registered_trees()?
+    .difference(&registered)
+    .filter(|tree_root| !seen.contains(*tree_root))
+    .map(|tree_root| tree_root.display().to_string())
+    .collect();
+  ensure!(
+    new_trees.is_empty(),
+    "trees were created while pruning, try again: {}",
+    new_trees.join(", ")
+  );
+
+  for (depot, project, _) in &unreferenced {
+    depot
+      .remove_mirrors(project)
+      .with_context(|| format!("failed to remove mirrors for {}", project))?;
+  }
+
+  println!("Deleted {} unused mirrors ({})", unreferenced.len(), format_size(total));
+  Ok(0)
+}
+
 // Sets GIT_TRACE2_PARENT_SID for matching multiple git traces to a single pore session
 //
 // See:
@@ -900,6 +1668,14 @@ fn cmd_info(config: &Config, tree: &Tree, paths: &[&Path]) -> Result<i32, Error>
       println!("Current branch: {}", branch);
     }
     println!("Manifest revision: {}", project.revision);
+    let remote_config = config.find_remote(&project.remote)?;
+    let depot = config.find_depot(&remote_config.depot)?;
+    if depot.is_partial(
+      remote_config,
+      &Depot::apply_project_renames(remote_config, &project.project_name),
+    ) {
+      println!("Partial clone: yes (file contents are fetched on demand, and not shared through the depot)");
+    }
     if local_branches.is_empty() {
       println!("Local Branches: 0");
     } else {
@@ -914,6 +1690,13 @@ fn cmd_info(config: &Config, tree: &Tree, paths: &[&Path]) -> Result<i32, Error>
   Ok(0)
 }
 
+/// Find the parallelism requested by the sync-j attribute of the manifest of the tree containing `cwd`, if any.
+fn manifest_sync_j(cwd: &Path) -> Option<i32> {
+  let tree = Tree::find_from_path(cwd).ok()?;
+  let manifest = tree.read_manifest().ok()?;
+  manifest.default?.sync_j?.try_into().ok()
+}
+
 fn main() {
   let args: Args = Args::parse();
 
@@ -937,6 +1720,12 @@ fn main() {
     }
   };
 
+  // Partial clones use a repository extension that libgit2 refuses to open repositories with by default.
+  // libgit2 can't fetch missing objects on demand, so operations that need them shell out to git instead.
+  if let Err(err) = unsafe { git2::opts::set_extensions(&["partialclone"]) } {
+    fatal!("failed to enable partial clone support in libgit2: {}", err);
+  }
+
   if let Some(cwd) = args.cwd {
     if let Err(err) = std::env::set_current_dir(&cwd) {
       fatal!("failed to set working directory to {}: {}", cwd.display(), err);
@@ -984,10 +1773,18 @@ fn main() {
     .jobs
     .or_else(|| {
       // Command-specific override
-      config.parallelism.get(cmd.to_string().as_str()).cloned().or_else(|| {
-        // Global override
-        config.parallelism.get("global").cloned()
-      })
+      config.parallelism.get(cmd.to_string().as_str()).cloned()
+    })
+    .or_else(|| {
+      // Manifest override
+      match cmd {
+        Commands::Fetch { .. } | Commands::Sync { .. } => manifest_sync_j(&cwd),
+        _ => None,
+      }
+    })
+    .or_else(|| {
+      // Global override
+      config.parallelism.get("global").cloned()
     })
     .unwrap_or(0);
 
@@ -1005,7 +1802,9 @@ fn main() {
   };
   let mut pool = Pool::with_size(pool_size);
 
-  let update_checker = if config.update_check && atty::is(Stream::Stdout) {
+  // Don't hold up git when running as a hook.
+  let is_hook = matches!(cmd, Commands::Hook { .. });
+  let update_checker = if config.update_check && !is_hook && atty::is(Stream::Stdout) {
     Some(UpdateChecker::fetch())
   } else {
     None
@@ -1034,6 +1833,7 @@ fn main() {
       }
       Commands::Checkout { branch } => {
         let tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
         tree.checkout(&config, &mut pool, &branch)
       }
       Commands::Clone {
@@ -1059,6 +1859,14 @@ fn main() {
         path,
       } => {
         let mut tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
+
+        // Make sure that depot maintenance knows about this tree even if the sync fails partway, since fetching starts
+        // using mirrors right away.
+        if let Err(err) = tree.register(&config) {
+          eprintln!("warning: failed to register tree with depot: {:#}", err);
+        }
+
         let fetch_tags = tags || fetch_all;
 
         let fetch_target = {
@@ -1079,6 +1887,7 @@ fn main() {
           fetch_target,
           CheckoutType::NoCheckout,
           false,
+          false,
           fetch_tags,
           false,
         )
@@ -1088,6 +1897,7 @@ fn main() {
         fetch_all,
         branch,
         detach,
+        rebase,
         refs_only,
         tags,
         path,
@@ -1095,6 +1905,13 @@ fn main() {
       } => {
         let fetch_type = if local { FetchType::NoFetch } else { FetchType::Fetch };
         let mut tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
+
+        // Make sure that depot maintenance knows about this tree even if the sync fails partway, since fetching starts
+        // using mirrors right away.
+        if let Err(err) = tree.register(&config) {
+          eprintln!("warning: failed to register tree with depot: {:#}", err);
+        }
 
         let fetch_tags = tags || fetch_all;
 
@@ -1120,6 +1937,7 @@ fn main() {
             CheckoutType::Checkout
           },
           detach,
+          rebase,
           fetch_tags,
           no_lfs,
         )
@@ -1138,6 +1956,7 @@ fn main() {
         path,
       } => {
         let tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
         tree.rebase(&config, &mut pool, interactive, autosquash, path)
       }
       Commands::Upload {
@@ -1148,12 +1967,20 @@ fn main() {
         cc,
         private,
         wip,
+        ready,
+        edit,
         branch_name_as_topic,
+        topic,
+        hashtags,
+        labels,
+        notify,
+        notify_to,
         autosubmit,
         no_autosubmit,
         presubmit,
         no_presubmit,
         ps_description,
+        dest,
         dry_run,
       } => {
         let tree = Tree::find_from_path(cwd)?;
@@ -1178,13 +2005,7 @@ fn main() {
             .unwrap_or("")
             .split(',')
             .filter(|r| !r.is_empty())
-            .map(|r| {
-              if r.contains('@') {
-                r.to_string()
-              } else {
-                format!("{}@google.com", r)
-              }
-            })
+            .map(|r| r.to_string())
             .collect()
         }
 
@@ -1198,23 +2019,56 @@ fn main() {
           &user_string_to_vec(cc.as_deref()),
           private,
           wip,
+          ready,
+          edit,
           branch_name_as_topic,
+          topic.as_deref(),
+          &hashtags,
+          &labels,
+          notify.map(|notify| notify.to_uppercase()).as_deref(),
+          &user_string_to_vec(notify_to.as_deref()),
           autosubmit_upload,
           presubmit_upload,
           ps_description.as_deref(),
+          dest.as_deref(),
           dry_run,
         )
       }
+      Commands::Abandon { branch, all, path } => {
+        let tree = Tree::find_from_path(cwd)?;
+        if all {
+          // Like repo, with --all, every positional argument is a project.
+          let paths: Vec<PathBuf> = branch
+            .into_iter()
+            .map(PathBuf::from)
+            .chain(path.unwrap_or_default())
+            .collect();
+          let paths = if paths.is_empty() { None } else { Some(paths) };
+          tree.abandon(&config, &mut pool, None, paths)
+        } else {
+          tree.abandon(&config, &mut pool, branch.as_deref(), path)
+        }
+      }
       Commands::Prune { path } => {
         let tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
         let remote_config = config.find_remote(&tree.config.remote)?;
         let depot = config.find_depot(&remote_config.depot)?;
 
         tree.prune(&config, &mut pool, &depot, path)
       }
-      Commands::Status { path, quiet } => {
+      Commands::Status { path, quiet, format } => {
+        let tree = Tree::find_from_path(cwd)?;
+        cmd_status(&config, &mut pool, &tree, path, quiet, format)
+      }
+      Commands::Diff {
+        path,
+        cached,
+        upstream,
+        stat,
+      } => {
         let tree = Tree::find_from_path(cwd)?;
-        cmd_status(&config, &mut pool, &tree, path, quiet)
+        tree.diff(&config, &mut pool, path, cached, upstream, stat)
       }
       Commands::Forall {
         path,
@@ -1226,6 +2080,17 @@ fn main() {
 
         tree.forall(&config, &mut pool, path, group_filters, command.as_str(), repo_compat)
       }
+      Commands::Grep {
+        pattern,
+        path,
+        group_filters,
+        ignore_case,
+      } => {
+        let tree = Tree::find_from_path(cwd)?;
+        let group_filters = group_filters.as_deref().map(parse_group_filters);
+
+        tree.grep(&config, &mut pool, path, group_filters, &pattern, ignore_case)
+      }
       Commands::Preupload { path } => {
         let tree = Tree::find_from_path(cwd)?;
         tree.preupload(&config, &mut pool, path)
@@ -1259,6 +2124,87 @@ fn main() {
         };
         cmd_info(&config, &tree, &paths_vec)
       }
+      Commands::Download {
+        cherry_pick,
+        revert,
+        ff_only,
+        project,
+        change,
+      } => {
+        let (change, patchset) = match change.split_once('/') {
+          Some((change, patchset)) => (change, Some(patchset)),
+          None => (change.as_str(), None),
+        };
+        let change = change
+          .parse::<u32>()
+          .with_context(|| format!("invalid change number '{}'", change))?;
+        let patchset = patchset
+          .map(|patchset| patchset.parse::<u32>())
+          .transpose()
+          .with_context(|| format!("invalid patchset number '{}'", patchset.unwrap_or_default()))?;
+
+        let mode = if cherry_pick {
+          DownloadMode::CherryPick
+        } else if revert {
+          DownloadMode::Revert
+        } else if ff_only {
+          DownloadMode::FastForward
+        } else {
+          DownloadMode::Checkout
+        };
+
+        let tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
+        tree.download(&config, &project, change, patchset, mode)
+      }
+      Commands::Sparse {
+        project,
+        patterns,
+        add,
+        disable,
+        reset,
+      } => {
+        let mut tree = Tree::find_from_path(cwd)?;
+        let _lock = tree.lock()?;
+        tree.sparse(&config, &project, patterns, add, disable, reset)
+      }
+      Commands::Depot { command } => match command {
+        DepotCommands::Gc { depot } => {
+          let depots = find_depots(&config, depot.as_deref())?;
+          cmd_depot_gc(&mut pool, &depots)
+        }
+        DepotCommands::Fsck {
+          depot,
+          connectivity,
+          repair,
+        } => {
+          let depots = find_depots(&config, depot.as_deref())?;
+          cmd_depot_fsck(&config, &mut pool, &depots, connectivity, repair)
+        }
+        DepotCommands::List { depot, sort, format } => {
+          let depots = find_depots(&config, depot.as_deref())?;
+          cmd_depot_list(&mut pool, &depots, sort, format)
+        }
+        DepotCommands::Prune {
+          depot,
+          scan,
+          yes,
+          trees,
+        } => {
+          let depots = find_depots(&config, depot.as_deref())?;
+          cmd_depot_prune(&config, &depots, trees, scan, yes)
+        }
+        DepotCommands::Trees { depot, prune } => {
+          let depots = find_depots(&config, depot.as_deref())?;
+          cmd_depot_trees(&depots, prune)
+        }
+      },
+      Commands::Hook { hook } => match hook {
+        HookCommands::CommitMsg { file } => {
+          hooks::commit_msg::run(&file)?;
+          Ok(0)
+        }
+      },
     }
   };
 
@@ -1270,6 +2216,7 @@ fn main() {
     Ok(rc) => std::process::exit(rc),
     Err(err) => {
       writeln!(&mut std::io::stderr(), "fatal: {:#}", err).unwrap();
+      std::process::exit(1);
     }
   }
 }
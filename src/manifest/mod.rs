@@ -46,15 +46,39 @@ pub struct Remote {
   pub alias: Option<String>,
   pub fetch: String,
   pub review: Option<String>,
+  pub pushurl: Option<String>,
   pub revision: Option<String>,
 }
 
+impl Remote {
+  /// Determine the URL to push a project to, if the manifest specifies one.
+  ///
+  /// pushurl takes precedence over review. review is only used if it explicitly names a push protocol (e.g. ssh://):
+  /// repo asks http(s) review servers and bare review hosts how to push via /ssh_info, which we don't, so those
+  /// fall back to the configured URL.
+  pub fn push_url(&self, project: &str) -> Option<String> {
+    let base = match (&self.pushurl, &self.review) {
+      (Some(pushurl), _) => pushurl.clone(),
+      (None, Some(review))
+        if review.contains("://") && !review.starts_with("http://") && !review.starts_with("https://") =>
+      {
+        review.clone()
+      }
+      (None, _) => return None,
+    };
+
+    Some(format!("{}/{}", canonicalize_url(&base), project))
+  }
+}
+
 #[derive(Default, Debug)]
 pub struct Default {
   pub revision: Option<String>,
   pub remote: Option<String>,
   pub sync_j: Option<u32>,
   pub sync_c: Option<bool>,
+  pub sync_s: Option<bool>,
+  pub sync_tags: Option<bool>,
 }
 
 #[derive(Debug)]
@@ -87,6 +111,8 @@ pub struct Project {
   pub groups: Option<Vec<String>>,
 
   pub sync_c: Option<bool>,
+  pub sync_s: Option<bool>,
+  pub sync_tags: Option<bool>,
   pub clone_depth: Option<u32>,
 
   pub file_operations: Vec<FileOperation>,
@@ -181,6 +207,60 @@ impl ExtendProject {
   }
 }
 
+#[derive(Default, Debug)]
+pub struct RemoveProject {
+  pub name: Option<String>,
+  pub path: Option<String>,
+  pub optional: bool,
+  pub base_rev: Option<String>,
+}
+
+impl RemoveProject {
+  pub fn remove(&self, manifest: &mut Manifest) -> Result<(), Error> {
+    let mut removed = Vec::new();
+    for (project_path, project) in &manifest.projects {
+      // If a name is specified, the path only serves to disambiguate between multiple checkouts of the project.
+      let matches = match (&self.name, &self.path) {
+        (Some(name), Some(path)) => project.name == *name && project.path() == path,
+        (Some(name), None) => project.name == *name,
+        (None, Some(path)) => project.path() == path,
+        (None, None) => bail!("remove-project has neither name nor path"),
+      };
+
+      if !matches {
+        continue;
+      }
+
+      if let Some(base_rev) = &self.base_rev {
+        let revision = project.find_revision(manifest)?;
+        if revision != *base_rev {
+          bail!(
+            "remove-project: revision of {} is {}, but base-rev is {}",
+            project.name,
+            revision,
+            base_rev
+          );
+        }
+      }
+
+      removed.push(project_path.clone());
+    }
+
+    if removed.is_empty() && !self.optional {
+      bail!(
+        "remove-project: no project matching {}",
+        self.name.as_deref().or(self.path.as_deref()).unwrap_or_default()
+      );
+    }
+
+    for project_path in removed {
+      manifest.projects.remove(&project_path);
+    }
+
+    Ok(())
+  }
+}
+
 #[derive(Clone, Debug)]
 pub enum FileOperation {
   LinkFile { src: String, dst: String },
@@ -218,6 +298,11 @@ impl Manifest {
     parser::parse(directory.as_ref(), file.as_ref())
   }
 
+  /// Parse a manifest on top of this one, as is done with repo's local manifests.
+  pub fn overlay(&mut self, directory: impl AsRef<Path>, file: impl AsRef<Path>) -> Result<(), Error> {
+    parser::parse_overlay(self, directory.as_ref(), file.as_ref())
+  }
+
   pub fn serialize(&self, output: Box<dyn Write>) -> Result<(), Error> {
     serializer::serialize(self, output)
   }
@@ -6,8 +6,8 @@ use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
 
 use crate::manifest::{
-  ContactInfo, Default, ExtendProject, FileOperation, Manifest, ManifestServer, Project, Remote, RepoHooks,
-  SuperProject,
+  ContactInfo, Default, ExtendProject, FileOperation, Manifest, ManifestServer, Project, Remote, RemoveProject,
+  RepoHooks, SuperProject,
 };
 
 /// Assign a value to an Option after asserting that it is None.
@@ -26,6 +26,10 @@ pub(crate) fn parse(directory: &Path, file: &Path) -> Result<Manifest, Error> {
   Ok(manifest)
 }
 
+pub(crate) fn parse_overlay(manifest: &mut Manifest, directory: &Path, file: &Path) -> Result<(), Error> {
+  parse_impl(manifest, directory, file)
+}
+
 fn parse_impl(manifest: &mut Manifest, directory: &Path, file: &Path) -> Result<(), Error> {
   let mut reader = Reader::from_file(file).with_context(|| format!("failed to read manifest file {:?}", file))?;
   reader.config_mut().trim_text(true);
@@ -97,12 +101,9 @@ fn parse_manifest(
           }
 
           b"project" => {
-            let project = parse_project(&e, reader, true)?;
-            let path = PathBuf::from(project.path());
-            if manifest.projects.contains_key(&path) {
-              bail!("duplicate project {:?}", path);
+            for project in parse_project(&e, reader, true, None)? {
+              insert_project(manifest, project)?;
             }
-            manifest.projects.insert(path, project);
           }
 
           _ => bail!("unexpected start tag in <manifest>: {:?}", tag_name),
@@ -117,12 +118,9 @@ fn parse_manifest(
           }
 
           b"project" => {
-            let project = parse_project(&e, reader, false)?;
-            let path = PathBuf::from(project.path());
-            if manifest.projects.contains_key(&path) {
-              bail!("duplicate project {:?}", path);
+            for project in parse_project(&e, reader, false, None)? {
+              insert_project(manifest, project)?;
             }
-            manifest.projects.insert(path, project);
           }
 
           b"extend-project" => {
@@ -136,6 +134,11 @@ fn parse_manifest(
             }
           }
 
+          b"remove-project" => {
+            let removal = parse_remove_project(&e, reader)?;
+            removal.remove(manifest)?;
+          }
+
           b"remote" => {
             let remote = parse_remote(&e, reader)?;
             if manifest.remotes.contains_key(&remote.name) {
@@ -176,6 +179,15 @@ fn parse_manifest(
   Ok(())
 }
 
+fn insert_project(manifest: &mut Manifest, project: Project) -> Result<(), Error> {
+  let path = PathBuf::from(project.path());
+  if manifest.projects.contains_key(&path) {
+    bail!("duplicate project {:?}", path);
+  }
+  manifest.projects.insert(path, project);
+  Ok(())
+}
+
 fn parse_notice(_event: &BytesStart, reader: &mut Reader<impl BufRead>) -> Result<String, Error> {
   let mut buf = Vec::new();
   let mut result = None;
@@ -253,11 +265,11 @@ fn parse_remote(event: &BytesStart, reader: &Reader<impl BufRead>) -> Result<Rem
       b"alias" => populate_option!(remote.alias, value),
       b"fetch" => populate_option!(fetch, value),
       b"review" => populate_option!(remote.review, value),
+      b"pushurl" => populate_option!(remote.pushurl, value),
       b"revision" => populate_option!(remote.revision, value),
 
       // Ignored: pore doesn't support direct pushing (yet?)
       b"push" => (),
-      b"pushurl" => (),
 
       _ => eprintln!("warning: unexpected attribute in <remote>: {:?}", key),
     }
@@ -281,6 +293,11 @@ fn parse_default(event: &BytesStart, reader: &Reader<impl BufRead>) -> Result<De
       b"remote" => populate_option!(default.remote, value),
       b"sync-j" => populate_option!(default.sync_j, value.parse::<u32>().context("failed to parse sync-j")?),
       b"sync-c" => populate_option!(default.sync_c, value.parse::<bool>().context("failed to parse sync-c")?),
+      b"sync-s" => populate_option!(default.sync_s, value.parse::<bool>().context("failed to parse sync-s")?),
+      b"sync-tags" => populate_option!(
+        default.sync_tags,
+        value.parse::<bool>().context("failed to parse sync-tags")?
+      ),
 
       b"upstream" => {
         // Ignored attribute. Used to limit the scope of the fetch with -c when a project is pinned
@@ -348,8 +365,15 @@ fn parse_contactinfo(event: &BytesStart, reader: &Reader<impl BufRead>) -> Resul
   })
 }
 
-fn parse_project(event: &BytesStart, reader: &mut Reader<impl BufRead>, has_children: bool) -> Result<Project, Error> {
+/// Parse a <project>, returning it followed by its nested subprojects, if any.
+fn parse_project(
+  event: &BytesStart,
+  reader: &mut Reader<impl BufRead>,
+  has_children: bool,
+  parent: Option<&Project>,
+) -> Result<Vec<Project>, Error> {
   let mut project = Project::default();
+  let mut subprojects = Vec::new();
   let mut name = None;
   for attribute in event.attributes() {
     let attribute = attribute?;
@@ -368,6 +392,11 @@ fn parse_project(event: &BytesStart, reader: &mut Reader<impl BufRead>, has_chil
       b"dest-branch" => populate_option!(project.dest_branch, value),
       b"groups" => populate_option!(project.groups, value.split(',').map(ToString::to_string).collect()),
       b"sync-c" => populate_option!(project.sync_c, value.parse::<bool>().context("failed to parse sync-c")?),
+      b"sync-s" => populate_option!(project.sync_s, value.parse::<bool>().context("failed to parse sync-s")?),
+      b"sync-tags" => populate_option!(
+        project.sync_tags,
+        value.parse::<bool>().context("failed to parse sync-tags")?
+      ),
       b"clone-depth" => populate_option!(
         project.clone_depth,
         value.parse::<u32>().context("failed to parse clone-depth")?
@@ -385,6 +414,13 @@ fn parse_project(event: &BytesStart, reader: &mut Reader<impl BufRead>, has_chil
 
   project.name = name.ok_or_else(|| anyhow!("name not specified in <project>"))?;
 
+  // Subprojects have their name and path relative to those of their parent.
+  if let Some(parent) = parent {
+    let path = format!("{}/{}", parent.path(), project.path());
+    project.name = format!("{}/{}", parent.name, project.name);
+    project.path = Some(path);
+  }
+
   if has_children {
     let mut buf = Vec::new();
     loop {
@@ -393,11 +429,19 @@ fn parse_project(event: &BytesStart, reader: &mut Reader<impl BufRead>, has_chil
         .with_context(|| format!("failed to parse XML at position {}", reader.buffer_position()))?;
 
       match event {
-        Event::Start(e) => bail!("unexpected start tag in <project>: {:?}", e.name()),
+        Event::Start(e) => {
+          let tag_name = e.name();
+          match tag_name.into_inner() {
+            b"project" => subprojects.extend(parse_project(&e, reader, true, Some(&project))?),
+            _ => bail!("unexpected start tag in <project>: {:?}", tag_name),
+          }
+        }
 
         Event::Empty(e) => {
           let tag_name = e.name();
           match tag_name.into_inner() {
+            b"project" => subprojects.extend(parse_project(&e, reader, false, Some(&project))?),
+
             b"copyfile" => {
               let op = parse_file_operation(&e, reader, true)?;
               project.file_operations.push(op);
@@ -460,7 +504,9 @@ fn parse_project(event: &BytesStart, reader: &mut Reader<impl BufRead>, has_chil
     }
   }
 
-  Ok(project)
+  let mut projects = vec![project];
+  projects.extend(subprojects);
+  Ok(projects)
 }
 
 fn parse_extend_project(event: &BytesStart, reader: &Reader<impl BufRead>) -> Result<ExtendProject, Error> {
@@ -490,6 +536,33 @@ fn parse_extend_project(event: &BytesStart, reader: &Reader<impl BufRead>) -> Re
   Ok(extensions)
 }
 
+fn parse_remove_project(event: &BytesStart, reader: &Reader<impl BufRead>) -> Result<RemoveProject, Error> {
+  let mut removal = RemoveProject::default();
+  for attribute in event.attributes() {
+    let attribute = attribute?;
+    let mut value = attribute.decode_and_unescape_value(reader.decoder())?.into_owned();
+    let key = attribute.key;
+    match key.into_inner() {
+      b"name" => {
+        while value.ends_with('/') {
+          value.pop();
+        }
+        populate_option!(removal.name, value)
+      }
+      b"path" => populate_option!(removal.path, value),
+      b"optional" => removal.optional = value.parse::<bool>().context("failed to parse optional")?,
+      b"base-rev" => populate_option!(removal.base_rev, value),
+      _ => eprintln!("warning: unexpected attribute in <remove-project>: {:?}", key),
+    }
+  }
+
+  if removal.name.is_none() && removal.path.is_none() {
+    bail!("neither name nor path specified in <remove-project>");
+  }
+
+  Ok(removal)
+}
+
 fn parse_file_operation(event: &BytesStart, reader: &Reader<impl BufRead>, copy: bool) -> Result<FileOperation, Error> {
   let op_name = if copy { "copyfile" } else { "linkfile" };
 
@@ -58,6 +58,7 @@ pub fn serialize(manifest: &Manifest, mut output: Box<dyn Write>) -> Result<()>
             populate_from_str(elem, "fetch", &remote.fetch);
             populate_from_str_option(elem, "alias", &remote.alias);
             populate_from_str_option(elem, "review", &remote.review);
+            populate_from_str_option(elem, "pushurl", &remote.pushurl);
           },
           |_| Ok(()),
         )?;
@@ -72,6 +73,8 @@ pub fn serialize(manifest: &Manifest, mut output: Box<dyn Write>) -> Result<()>
             populate_from_str_option(elem, "remote", &default.remote);
             populate_from_option(elem, "sync-j", &default.sync_j);
             populate_from_option(elem, "sync-c", &default.sync_c);
+            populate_from_option(elem, "sync-s", &default.sync_s);
+            populate_from_option(elem, "sync-tags", &default.sync_tags);
           },
           |_| Ok(()),
         )?;
@@ -111,6 +114,8 @@ pub fn serialize(manifest: &Manifest, mut output: Box<dyn Write>) -> Result<()>
             populate_from_str_option(elem, "revision", &project.revision);
             populate_from_option(elem, "dest-branch", &project.dest_branch);
             populate_from_option(elem, "sync-c", &project.sync_c);
+            populate_from_option(elem, "sync-s", &project.sync_s);
+            populate_from_option(elem, "sync-tags", &project.sync_tags);
             populate_from_option(elem, "clone-depth", &project.clone_depth);
             if let Some(groups) = &project.groups {
               populate(elem, "groups", groups.join(",").into_bytes())
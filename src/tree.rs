@@ -14,9 +14,10 @@
  * limitations under the License.
  */
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt;
-use std::io::Write;
+use std::fs::File;
+use std::io::{Read as _, Seek as _, Write};
 use std::iter::FromIterator;
 use std::ops::Deref;
 #[cfg(unix)]
@@ -24,6 +25,7 @@ use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Error};
+use fs4::fs_std::FileExt as _;
 use progpool::{ExecutionResult, ExecutionResults, Job, Pool};
 use url::Url;
 use walkdir::WalkDir;
@@ -40,6 +42,75 @@ pub struct Tree {
   pub config: TreeConfig,
 }
 
+/// How long to wait for another pore process to release a tree's lock before giving up.
+const TREE_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// An advisory lock on a tree, held by commands that modify it.
+///
+/// The lock file records the command line of the process holding it, so that waiters can report who they're
+/// waiting on. The lock is released when this is dropped.
+pub struct TreeLock {
+  file: File,
+}
+
+impl TreeLock {
+  fn acquire(tree_root: &Path) -> Result<TreeLock, Error> {
+    let lock_path = tree_root.join(".pore").join("lock");
+    let mut file = std::fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&lock_path)
+      .with_context(|| format!("failed to open lock file {:?}", lock_path))?;
+
+    let start = std::time::Instant::now();
+    let mut warned = false;
+    loop {
+      match file.try_lock_exclusive() {
+        Ok(()) => break,
+        Err(err) if err.kind() == fs4::lock_contended_error().kind() => {}
+        Err(err) => return Err(Error::from(err).context(format!("failed to lock {:?}", lock_path))),
+      }
+
+      let mut holder = String::new();
+      file.rewind().context("failed to seek lock file")?;
+      file.read_to_string(&mut holder).context("failed to read lock file")?;
+      let holder = match holder.trim() {
+        "" => "another pore process".to_string(),
+        holder => format!("`{}`", holder),
+      };
+
+      if start.elapsed() >= TREE_LOCK_TIMEOUT {
+        bail!(
+          "timed out after {}s waiting for tree lock held by {}",
+          TREE_LOCK_TIMEOUT.as_secs(),
+          holder
+        );
+      }
+
+      if !warned {
+        eprintln!("waiting for tree lock held by {}", holder);
+        warned = true;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+
+    let command: Vec<String> = std::env::args().collect();
+    file.set_len(0).context("failed to truncate lock file")?;
+    file.rewind().context("failed to seek lock file")?;
+    writeln!(file, "{} (pid {})", command.join(" "), std::process::id()).context("failed to write lock file")?;
+    Ok(TreeLock { file })
+  }
+}
+
+impl Drop for TreeLock {
+  fn drop(&mut self) {
+    let _ = self.file.set_len(0);
+    let _ = self.file.unlock();
+  }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum FetchType {
   /// Fetch the manifest, then fetch everything.
@@ -105,6 +176,22 @@ impl FetchTarget {
   }
 }
 
+/// How `pore download` applies a change to a project.
+#[derive(Copy, Clone, PartialEq)]
+pub enum DownloadMode {
+  /// Check out the change, detaching HEAD.
+  Checkout,
+
+  /// Cherry-pick the change onto HEAD.
+  CherryPick,
+
+  /// Revert the change on top of HEAD.
+  Revert,
+
+  /// Fast-forward HEAD to the change.
+  FastForward,
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub enum CheckoutType {
   Checkout,
@@ -264,19 +351,37 @@ pub struct TreeConfig {
 
   pub projects: Vec<String>,
   pub group_filters: Option<Vec<GroupFilter>>,
+
+  /// Sparse checkout patterns for projects, overriding the manifest's. An empty list checks out everything.
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub sparse: BTreeMap<String, Vec<String>>,
 }
 
+/// Manifest annotation with a whitespace separated list of a project's sparse checkout patterns.
+const SPARSE_CHECKOUT_ANNOTATION: &str = "pore-sparse-checkout";
+
 #[derive(Clone, Debug)]
 pub struct ProjectInfo {
   pub project_path: String,
   pub project_name: String,
   pub remote: String,
+  pub push_url: Option<String>,
   pub revision: String,
+  pub clone_depth: Option<u32>,
+  /// Whether to fetch only the upstream revision, rather than all refs (sync-c).
+  pub sync_c: bool,
+  /// Whether to fetch submodules (sync-s).
+  pub sync_s: bool,
+  /// Whether tags may be fetched (sync-tags).
+  pub sync_tags: bool,
   pub file_ops: Vec<manifest::FileOperation>,
   pub manifest_project: bool,
+  /// Patterns of the files to check out, if only some of them should be.
+  pub sparse_checkout: Option<Vec<String>>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum FileState {
   New,
   Modified,
@@ -299,19 +404,26 @@ impl FileState {
   }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct FileStatus {
   pub filename: String,
   pub index: FileState,
   pub worktree: FileState,
 }
 
-#[derive(Debug)]
+fn serialize_oid<S>(oid: &git2::Oid, serializer: S) -> Result<S::Ok, S::Error>
+where
+  S: serde::Serializer,
+{
+  serializer.collect_str(oid)
+}
+
+#[derive(Debug, Serialize)]
 pub struct ProjectStatus {
-  #[allow(dead_code)]
   pub name: String,
   pub path: String,
   pub branch: Option<String>,
+  #[serde(serialize_with = "serialize_oid")]
   pub commit: git2::Oid,
   pub commit_summary: Option<String>,
   pub files: Vec<FileStatus>,
@@ -382,6 +494,21 @@ impl<'repo> BranchInfo<'repo> {
   }
 }
 
+/// What to run repo's preupload hooks on.
+struct PreuploadTarget<'a> {
+  name: String,
+  project_path: PathBuf,
+  commits: PreuploadCommits<'a>,
+}
+
+enum PreuploadCommits<'a> {
+  /// The commits on HEAD that aren't in the manifest revision, if HEAD is a branch.
+  Head { remote: &'a str, revision: &'a str },
+
+  /// A specific list of commits.
+  Explicit(Vec<git2::Oid>),
+}
+
 struct CommitSummary {
   pub id: git2::Oid,
   pub summary: String,
@@ -392,39 +519,67 @@ struct UploadSummary {
   pub src_branch: String,
   pub dest_remote: String,
   pub dest_branch: String,
+  pub push_url: String,
+  pub push_options: Vec<String>,
   pub commit_summaries: Vec<CommitSummary>,
 }
 
-fn confirm_upload(upload_summaries: Vec<&UploadSummary>, autosubmit: bool) -> Result<(), Error> {
+/// Find the commits on a local branch that aren't in upstream.
+fn find_branch_commits(repo: &git2::Repository, branch: &str, upstream: git2::Oid) -> Result<Vec<git2::Oid>, Error> {
+  let branch = repo
+    .find_branch(branch, git2::BranchType::Local)
+    .context("failed to find branch")?;
+  let tip = branch.get().peel_to_commit().context("failed to resolve branch")?;
+  let upstream = repo.find_commit(upstream).context("failed to find upstream commit")?;
+  util::find_independent_commits(repo, &tip, &upstream)
+}
+
+/// Describe an upload: a header line for the branch, followed by a line for each commit.
+fn describe_upload(upload_summary: &UploadSummary) -> Vec<String> {
   let mut lines: Vec<String> = Vec::new();
-  for upload_summary in upload_summaries {
-    let is_aosp = upload_summary.dest_remote == "aosp";
+  let is_aosp = upload_summary.dest_remote == "aosp";
+  lines.push(format!(
+    "{}: {} commit{} from branch {} to {}{}{} ({})",
+    project_style().apply_to(&upload_summary.project_path),
+    upload_summary.commit_summaries.len(),
+    if upload_summary.commit_summaries.len() == 1 {
+      ""
+    } else {
+      "s"
+    },
+    branch_style().apply_to(&upload_summary.src_branch),
+    if is_aosp {
+      aosp_remote_style().apply_to(&upload_summary.dest_remote)
+    } else {
+      non_aosp_remote_style().apply_to(&upload_summary.dest_remote)
+    },
+    slash_style().apply_to("/"),
+    branch_style().apply_to(&upload_summary.dest_branch),
+    upload_summary.push_url,
+  ));
+
+  if !upload_summary.push_options.is_empty() {
     lines.push(format!(
-      "{}: {} commit{} from branch {} to {}{}{}",
-      project_style().apply_to(&upload_summary.project_path),
-      upload_summary.commit_summaries.len(),
-      if upload_summary.commit_summaries.len() == 1 {
-        ""
-      } else {
-        "s"
-      },
-      branch_style().apply_to(&upload_summary.src_branch),
-      if is_aosp {
-        aosp_remote_style().apply_to(&upload_summary.dest_remote)
-      } else {
-        non_aosp_remote_style().apply_to(&upload_summary.dest_remote)
-      },
-      slash_style().apply_to("/"),
-      branch_style().apply_to(&upload_summary.dest_branch),
+      "  {} {}",
+      console::style("options:").dim(),
+      upload_summary.push_options.join(", ")
     ));
+  }
 
-    for commit_summary in &upload_summary.commit_summaries {
-      lines.push(format!(
-        "  {:.10} {}",
-        console::style(commit_summary.id).cyan(),
-        commit_summary.summary
-      ))
-    }
+  for commit_summary in &upload_summary.commit_summaries {
+    lines.push(format!(
+      "  {:.10} {}",
+      console::style(commit_summary.id).cyan(),
+      commit_summary.summary
+    ))
+  }
+  lines
+}
+
+fn confirm_upload(upload_summaries: Vec<&UploadSummary>, autosubmit: bool) -> Result<(), Error> {
+  let mut lines: Vec<String> = Vec::new();
+  for upload_summary in upload_summaries {
+    lines.extend(describe_upload(upload_summary));
   }
 
   lines.push(format!(
@@ -451,12 +606,60 @@ fn confirm_upload(upload_summaries: Vec<&UploadSummary>, autosubmit: bool) -> Re
   }
 }
 
+/// Let the user pick which branches to upload by uncommenting them in their editor.
+///
+/// Returns the indices of the selected uploads.
+fn select_uploads(
+  edit_path: &Path,
+  upload_summaries: Vec<&UploadSummary>,
+  autosubmit: bool,
+) -> Result<HashSet<usize>, Error> {
+  let mut contents = String::from("# Uncomment the branches to upload:\n");
+  if autosubmit {
+    contents += "# (autosubmit enabled)\n";
+  }
+
+  let mut headers = HashMap::new();
+  for (i, upload_summary) in upload_summaries.iter().enumerate() {
+    contents += "#\n";
+    for line in describe_upload(upload_summary) {
+      let line = console::strip_ansi_codes(&line).to_string();
+      contents += &format!("# {}\n", line);
+      if !line.starts_with(' ') {
+        headers.insert(line, i);
+      }
+    }
+  }
+
+  let contents = util::edit_file(edit_path, &contents)?;
+
+  let mut selected = HashSet::new();
+  for line in contents.lines() {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') || line.starts_with(' ') {
+      continue;
+    }
+
+    match headers.get(line) {
+      Some(i) => {
+        selected.insert(*i);
+      }
+      None => bail!("unrecognized line in branch selection: {}", line),
+    }
+  }
+
+  ensure!(!selected.is_empty(), "upload aborted by user: no branches selected");
+  Ok(selected)
+}
+
 fn summarize_upload(
   project_path: &str,
   repo: &git2::Repository,
   src: &BranchInfo,
   dest: &BranchInfo,
   dest_remote: &str,
+  push_url: &str,
+  push_options: Vec<String>,
   commits: &[git2::Oid],
 ) -> Result<UploadSummary, Error> {
   let mut commit_summaries: Vec<CommitSummary> = Vec::new();
@@ -479,10 +682,115 @@ fn summarize_upload(
     src_branch: src.name.to_string(),
     dest_remote: dest_remote.to_string(),
     dest_branch: dest.name_without_remote().to_string(),
+    push_url: push_url.to_string(),
+    push_options,
     commit_summaries,
   })
 }
 
+/// Sort the results of a job by the order of their projects in the manifest.
+fn sort_by_project_order<T>(results: &mut [ExecutionResult<T>], projects: &[ProjectInfo]) {
+  let order: HashMap<&str, usize> = projects
+    .iter()
+    .enumerate()
+    .map(|(i, project)| (project.project_path.as_str(), i))
+    .collect();
+  results.sort_by_key(|result| order.get(result.name.as_str()).copied());
+}
+
+fn render_diff_patch(diff: &git2::Diff) -> Result<Vec<u8>, Error> {
+  let mut output = Vec::new();
+  diff
+    .print(git2::DiffFormat::Patch, |_delta, _hunk, line| {
+      let content = String::from_utf8_lossy(line.content());
+      let content = content.trim_end_matches('\n');
+      let rendered = match line.origin() {
+        '+' => console::style(format!("+{}", content)).green().to_string(),
+        '-' => console::style(format!("-{}", content)).red().to_string(),
+        ' ' => format!(" {}", content),
+        'F' => console::style(content).bold().to_string(),
+        'H' => console::style(content).cyan().to_string(),
+        _ => content.to_string(),
+      };
+      output.extend(rendered.as_bytes());
+      output.push(b'\n');
+      true
+    })
+    .context("failed to print diff")?;
+  Ok(output)
+}
+
+fn render_diff_stat(diff: &git2::Diff, project_path: &str) -> Result<Vec<u8>, Error> {
+  // Scale the graph down if there are too many changes to fit.
+  const GRAPH_WIDTH: usize = 40;
+
+  let mut files = Vec::new();
+  for (i, delta) in diff.deltas().enumerate() {
+    let path = delta
+      .new_file()
+      .path()
+      .or_else(|| delta.old_file().path())
+      .ok_or_else(|| format_err!("diff delta has no path"))?;
+    let (additions, deletions) = match git2::Patch::from_diff(diff, i).context("failed to create patch")? {
+      Some(patch) => {
+        let (_, additions, deletions) = patch.line_stats().context("failed to get patch stats")?;
+        (additions, deletions)
+      }
+      None => (0, 0),
+    };
+    files.push((Path::new(project_path).join(path), additions, deletions));
+  }
+
+  let mut output = Vec::new();
+  if files.is_empty() {
+    return Ok(output);
+  }
+
+  let name_width = files
+    .iter()
+    .map(|(name, _, _)| name.display().to_string().len())
+    .max()
+    .unwrap_or(0);
+  let max_changes = files
+    .iter()
+    .map(|(_, additions, deletions)| additions + deletions)
+    .max()
+    .unwrap_or(0);
+  for (name, additions, deletions) in &files {
+    let (plus, minus) = if max_changes > GRAPH_WIDTH {
+      (
+        additions * GRAPH_WIDTH / max_changes,
+        deletions * GRAPH_WIDTH / max_changes,
+      )
+    } else {
+      (*additions, *deletions)
+    };
+    writeln!(
+      output,
+      " {:width$} | {:>5} {}{}",
+      name.display().to_string(),
+      additions + deletions,
+      console::style("+".repeat(plus)).green(),
+      console::style("-".repeat(minus)).red(),
+      width = name_width
+    )?;
+  }
+
+  let total_additions: usize = files.iter().map(|(_, additions, _)| additions).sum();
+  let total_deletions: usize = files.iter().map(|(_, _, deletions)| deletions).sum();
+  writeln!(
+    output,
+    " {} file{} changed, {} insertion{}(+), {} deletion{}(-)",
+    files.len(),
+    if files.len() == 1 { "" } else { "s" },
+    total_additions,
+    if total_additions == 1 { "" } else { "s" },
+    total_deletions,
+    if total_deletions == 1 { "" } else { "s" },
+  )?;
+  Ok(output)
+}
+
 impl Tree {
   pub fn construct<T: Into<PathBuf>>(
     depot: &Depot,
@@ -493,14 +801,16 @@ impl Tree {
     file: &str,
     group_filters: Vec<GroupFilter>,
     fetch: bool,
-  ) -> Result<Tree, Error> {
+  ) -> Result<(Tree, TreeLock), Error> {
     let tree_root = path.into();
 
-    // TODO: Add locking?
     util::assert_empty_directory(&tree_root)?;
     let pore_path = tree_root.join(".pore");
 
-    std::fs::create_dir_all(&pore_path).with_context(|| format!("failed to create directory {:?}", pore_path))?;
+    // Use create_dir instead of create_dir_all so that only one of several concurrent clones into the same directory
+    // gets to construct the tree.
+    std::fs::create_dir(&pore_path).with_context(|| format!("failed to create directory {:?}", pore_path))?;
+    let lock = TreeLock::acquire(&tree_root)?;
 
     let manifest_path = pore_path.join("manifest");
     let manifest_file = PathBuf::from("manifest").join(file);
@@ -516,7 +826,7 @@ impl Tree {
         None,
       )?;
     }
-    depot.clone_repo(remote_config, manifest_project, branch, &manifest_path)?;
+    depot.clone_repo(remote_config, manifest_project, None, None, branch, &manifest_path)?;
 
     let tree_config = TreeConfig {
       remote: remote_config.name.clone(),
@@ -525,6 +835,7 @@ impl Tree {
       tags: Vec::new(),
       projects: Vec::new(),
       group_filters: Some(group_filters),
+      sparse: BTreeMap::new(),
     };
 
     let tree = Tree {
@@ -533,7 +844,251 @@ impl Tree {
     };
 
     tree.write_config()?;
-    Ok(tree)
+    depot.register_tree(&tree.path)?;
+    Ok((tree, lock))
+  }
+
+  /// Find the object directories that the checkouts in the tree use as alternates.
+  ///
+  /// This includes deleted projects that were moved to lost+found, which still depend on their mirrors.
+  pub fn alternates(&self) -> Result<HashSet<PathBuf>, Error> {
+    Ok(self.checkouts_by_alternate()?.into_keys().collect())
+  }
+
+  /// Map the canonical paths of the alternate object directories used by the tree to the git directories using them.
+  pub fn checkouts_by_alternate(&self) -> Result<HashMap<PathBuf, Vec<PathBuf>>, Error> {
+    let mut git_dirs: Vec<PathBuf> = self
+      .config
+      .projects
+      .iter()
+      .map(|project| self.path.join(project).join(".git"))
+      .collect();
+    git_dirs.push(self.path.join(".pore").join("manifest").join(".git"));
+
+    let lost_and_found = self.path.join("lost+found");
+    if lost_and_found.exists() {
+      for entry in WalkDir::new(&lost_and_found) {
+        let entry = entry.with_context(|| format!("failed to walk {:?}", lost_and_found))?;
+        if entry.file_type().is_dir() && entry.file_name() == ".git" {
+          git_dirs.push(entry.into_path());
+        }
+      }
+    }
+
+    let mut result: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for git_dir in git_dirs {
+      let alternates_path = git_dir.join("objects").join("info").join("alternates");
+      let alternates = match std::fs::read_to_string(&alternates_path) {
+        Ok(alternates) => alternates,
+        Err(_) => continue,
+      };
+
+      for alternate in alternates
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      {
+        let alternate = git_dir.join("objects").join(alternate);
+        if let Ok(alternate) = std::fs::canonicalize(&alternate) {
+          result.entry(alternate).or_default().push(git_dir.clone());
+        }
+      }
+    }
+    Ok(result)
+  }
+
+  /// Find a project in the manifest by its path, or failing that, by its name.
+  fn find_manifest_project<'a>(
+    &self,
+    manifest: &'a Manifest,
+    project: &str,
+  ) -> Result<(&'a PathBuf, &'a manifest::Project), Error> {
+    if Path::new(project).exists() {
+      let tree_root = std::fs::canonicalize(&self.path).context("failed to canonicalize tree path")?;
+      let requested_path =
+        std::fs::canonicalize(project).with_context(|| format!("failed to canonicalize path '{}'", project))?;
+      let relative_path = pathdiff::diff_paths(&requested_path, &tree_root)
+        .ok_or_else(|| format_err!("failed to calculate path diff for {}", project))?;
+
+      // Pick the innermost project containing the path.
+      if let Some(result) = manifest
+        .projects
+        .iter()
+        .filter(|(project_path, _)| relative_path.starts_with(project_path))
+        .max_by_key(|(project_path, _)| project_path.as_os_str().len())
+      {
+        return Ok(result);
+      }
+    }
+
+    let mut matches = manifest.projects.iter().filter(|(_, p)| p.name == project);
+    match (matches.next(), matches.next()) {
+      (Some(result), None) => Ok(result),
+      (Some(_), Some(_)) => bail!("project name {} is ambiguous, specify its path instead", project),
+      (None, _) => bail!("failed to find project {}", project),
+    }
+  }
+
+  /// Fetch a change from Gerrit through the depot, and apply it to a project.
+  pub fn download(
+    &self,
+    config: &Config,
+    project: &str,
+    change: u32,
+    patchset: Option<u32>,
+    mode: DownloadMode,
+  ) -> Result<i32, Error> {
+    let manifest = self.read_manifest()?;
+    let (project_path, project) = self.find_manifest_project(&manifest, project)?;
+    let (remote, _) = manifest.resolve_project_remote(config, &self.config, project)?;
+    let remote_config = config.find_remote(&remote)?;
+    let depot = config.find_depot(&remote_config.depot)?;
+
+    // Find the commit through ls-remote: we need it to find the latest patchset anyway, and the ref that fetch_repo
+    // leaves behind in the mirror might be clobbered by a concurrent fetch.
+    let change_prefix = format!("refs/changes/{:02}/{}/", change % 100, change);
+    let repo_url = format!("{}{}.git", remote_config.url, project.name);
+    let git_output = std::process::Command::new("git")
+      .arg("ls-remote")
+      .arg(&repo_url)
+      .arg(format!("{}*", change_prefix))
+      .env(
+        "GIT_SSH_COMMAND",
+        format!("ssh -o 'ControlMaster no' -o 'ControlPath {}'", util::ssh_mux_path()),
+      )
+      .output()
+      .context("failed to spawn git ls-remote")?;
+    if !git_output.status.success() {
+      bail!("git ls-remote failed: {}", String::from_utf8_lossy(&git_output.stderr));
+    }
+
+    let mut patchsets = BTreeMap::new();
+    for line in String::from_utf8_lossy(&git_output.stdout).lines() {
+      let (oid, refname) = line
+        .split_once('\t')
+        .ok_or_else(|| format_err!("unexpected output from git ls-remote: {}", line))?;
+      // Skip refs/changes/NN/CHANGE/meta and friends.
+      if let Some(Ok(ps)) = refname.strip_prefix(&change_prefix).map(str::parse::<u32>) {
+        patchsets.insert(ps, git2::Oid::from_str(oid)?);
+      }
+    }
+
+    let (patchset, oid) = match patchset {
+      Some(patchset) => (
+        patchset,
+        *patchsets
+          .get(&patchset)
+          .ok_or_else(|| format_err!("change {} has no patchset {}", change, patchset))?,
+      ),
+      None => patchsets
+        .iter()
+        .next_back()
+        .map(|(ps, oid)| (*ps, *oid))
+        .ok_or_else(|| format_err!("change {} not found in {}", change, project.name))?,
+    };
+    let change_ref = format!("{}{}", change_prefix, patchset);
+
+    println!(
+      "Downloading change {}/{} into {}",
+      change,
+      patchset,
+      project_style().apply_to(project_path.display())
+    );
+    depot.fetch_repo(
+      remote_config,
+      &project.name,
+      Some(&[change_ref.clone()]),
+      false,
+      project.clone_depth,
+    )?;
+
+    let path = self.path.join(project_path);
+    depot.update_remote_refs(remote_config, &project.name, &path)?;
+
+    let repo = git2::Repository::open(&path).with_context(|| format!("failed to open repository {:?}", path))?;
+    repo
+      .find_commit(oid)
+      .with_context(|| format!("failed to find {} after fetching {}", oid, change_ref))?;
+
+    let mut cmd = std::process::Command::new("git");
+    cmd.current_dir(&path);
+    match mode {
+      DownloadMode::Checkout => cmd.arg("checkout").arg("--detach"),
+      DownloadMode::CherryPick => cmd.arg("cherry-pick"),
+      DownloadMode::Revert => cmd.arg("revert").arg("--no-edit"),
+      DownloadMode::FastForward => cmd.arg("merge").arg("--ff-only"),
+    };
+    let status = cmd.arg(oid.to_string()).status().context("failed to spawn git")?;
+    if !status.success() {
+      bail!(
+        "failed to apply change {}/{} to {}",
+        change,
+        patchset,
+        project_path.display()
+      );
+    }
+
+    Ok(0)
+  }
+
+  /// Show or change the sparse checkout patterns of a project, and apply them.
+  pub fn sparse(
+    &mut self,
+    config: &Config,
+    path: &Path,
+    patterns: Vec<String>,
+    add: bool,
+    disable: bool,
+    reset: bool,
+  ) -> Result<i32, Error> {
+    let manifest = self.read_manifest()?;
+    let find_project = |tree: &Tree| -> Result<ProjectInfo, Error> {
+      // Pick the innermost project containing the path.
+      tree
+        .collect_manifest_projects(config, &manifest, Some(vec![path.to_path_buf()]), None)?
+        .into_iter()
+        .max_by_key(|project| project.project_path.len())
+        .ok_or_else(|| format_err!("failed to find project containing {:?}", path))
+    };
+    let project = find_project(self)?;
+
+    if patterns.is_empty() && !disable && !reset {
+      match &project.sparse_checkout {
+        Some(patterns) => {
+          for pattern in patterns {
+            println!("{}", pattern);
+          }
+        }
+        None => eprintln!("{} is entirely checked out", project.project_path),
+      }
+      return Ok(0);
+    }
+
+    if reset {
+      self.config.sparse.remove(&project.project_path);
+    } else if disable {
+      self.config.sparse.insert(project.project_path.clone(), Vec::new());
+    } else {
+      let mut new_patterns = if add {
+        project.sparse_checkout.clone().unwrap_or_default()
+      } else {
+        Vec::new()
+      };
+      new_patterns.extend(patterns);
+      self.config.sparse.insert(project.project_path.clone(), new_patterns);
+    }
+    self.write_config().context("failed to write tree config")?;
+
+    let project = find_project(self)?;
+    let repo = git2::Repository::open(self.path.join(&project.project_path))
+      .with_context(|| format!("failed to open repository {}", project.project_path))?;
+    util::set_sparse_checkout(&repo, project.sparse_checkout.as_deref(), true)
+      .with_context(|| format!("failed to update sparse checkout of {}", project.project_path))?;
+    Ok(0)
+  }
+
+  /// Take the tree's lock, waiting for a bounded amount of time if another command is holding it.
+  pub fn lock(&self) -> Result<TreeLock, Error> {
+    TreeLock::acquire(&self.path)
   }
 
   pub fn from_path<T: Into<PathBuf>>(path: T) -> Result<Tree, Error> {
@@ -560,6 +1115,14 @@ impl Tree {
     Tree::from_path(path)
   }
 
+  /// Register the tree with the depot of its manifest's remote.
+  ///
+  /// `pore sync` registers the tree with the depots of all of its projects as well.
+  pub fn register(&self, config: &Config) -> Result<(), Error> {
+    let remote_config = config.find_remote(&self.config.remote)?;
+    config.find_depot(&remote_config.depot)?.register_tree(&self.path)
+  }
+
   fn write_config(&self) -> Result<(), Error> {
     let text = toml::to_string_pretty(&self.config).context("failed to serialize tree config")?;
     std::fs::write(self.path.join(".pore").join("tree.toml"), text).context("failed to write tree config")
@@ -575,10 +1138,59 @@ impl Tree {
   pub fn read_manifest(&self) -> Result<Manifest, Error> {
     let manifest_path = self.path.join(".pore").join("manifest");
     let manifest_file = self.path.join(".pore").join("manifest.xml");
-    let manifest = Manifest::parse(&manifest_path, &manifest_file).context("failed to read manifest")?;
+    let mut manifest = Manifest::parse(&manifest_path, &manifest_file).context("failed to read manifest")?;
+
+    // Like repo, apply local manifests on top of the manifest in order of their filenames. .repo/local_manifests is
+    // normally a symlink to .pore/local_manifests, but if something created it as a directory first, use it as well.
+    let mut local_manifests_paths = vec![self.path.join(".pore").join("local_manifests")];
+    let repo_local_manifests_path = self.path.join(".repo").join("local_manifests");
+    if std::fs::symlink_metadata(&repo_local_manifests_path).map_or(false, |metadata| metadata.is_dir()) {
+      local_manifests_paths.push(repo_local_manifests_path);
+    }
+
+    let mut local_manifests = Vec::new();
+    for local_manifests_path in local_manifests_paths {
+      if !local_manifests_path.is_dir() {
+        continue;
+      }
+
+      let entries = std::fs::read_dir(&local_manifests_path)
+        .with_context(|| format!("failed to read local manifests directory {:?}", local_manifests_path))?;
+      for entry in entries {
+        let path = entry.context("failed to read local manifests directory entry")?.path();
+        if path.extension().map_or(false, |ext| ext == "xml") {
+          local_manifests.push(path);
+        }
+      }
+    }
+    local_manifests.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    for local_manifest in local_manifests {
+      manifest
+        .overlay(&manifest_path, &local_manifest)
+        .with_context(|| format!("failed to read local manifest {:?}", local_manifest))?;
+    }
+
     Ok(manifest)
   }
 
+  /// Resolve paths requested on the command line to paths relative to the root of the tree.
+  fn relative_paths(&self, paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    // The correctness of this seems dubious if the paths are accessed via symlinks or mount points,
+    // but repo doesn't handle this either.
+    let tree_root = std::fs::canonicalize(&self.path).context("failed to canonicalize tree path")?;
+    let mut result = Vec::new();
+    for path in paths {
+      let requested_path = std::fs::canonicalize(path)
+        .with_context(|| format!("failed to canonicalize requested path '{}'", path.display()))?;
+      result.push(
+        pathdiff::diff_paths(&requested_path, &tree_root)
+          .ok_or_else(|| format_err!("failed to calculate path diff for {}", path.display()))?,
+      );
+    }
+    Ok(result)
+  }
+
   pub fn collect_manifest_projects(
     &self,
     config: &Config,
@@ -593,19 +1205,7 @@ impl Tree {
       .unwrap_or_else(|| self.config.branch.clone());
 
     let group_filters = self.config.group_filters.as_deref().unwrap_or(&[]);
-
-    // The correctness of this seems dubious if the paths are accessed via symlinks or mount points,
-    // but repo doesn't handle this either.
-    let tree_root = std::fs::canonicalize(&self.path).context("failed to canonicalize tree path")?;
-    let mut paths = Vec::new();
-    for path in under.unwrap_or_default() {
-      let requested_path = std::fs::canonicalize(&path)
-        .with_context(|| format!("failed to canonicalize requested path '{}'", path.display()))?;
-      paths.push(
-        pathdiff::diff_paths(&requested_path, &tree_root)
-          .ok_or_else(|| format_err!("failed to calculate path diff for {}", path.display()))?,
-      );
-    }
+    let paths = self.relative_paths(&under.unwrap_or_default())?;
 
     let filtered_projects = manifest
       .projects
@@ -617,6 +1217,7 @@ impl Tree {
         paths.is_empty() || paths.iter().any(|path| Path::new(path).starts_with(project_path))
       });
 
+    let default = manifest.default.as_ref();
     let mut projects = Vec::new();
     for (project_path, project) in filtered_projects {
       let (remote, remote_config) = manifest.resolve_project_remote(config, &self.config, project)?;
@@ -627,13 +1228,38 @@ impl Tree {
         .or_else(|| manifest.default.as_ref().and_then(|m| m.revision.clone()))
         .unwrap_or_else(|| default_revision.clone());
 
+      let project_path_str = project_path.to_str().expect("project path not UTF-8");
+      let sparse_checkout = match self.config.sparse.get(project_path_str) {
+        Some(patterns) => Some(patterns.clone()),
+        None => project
+          .annotations
+          .get(SPARSE_CHECKOUT_ANNOTATION)
+          .map(|patterns| patterns.split_whitespace().map(str::to_string).collect()),
+      };
+
       projects.push(ProjectInfo {
-        project_path: project_path.to_str().expect("project path not UTF-8").into(),
+        project_path: project_path_str.into(),
         project_name: project.name.clone(),
         remote,
+        push_url: remote_config.push_url(&project.name),
         revision,
+        clone_depth: project.clone_depth,
+        // Unlike repo, pore defaults to fetching only the upstream revision.
+        sync_c: project
+          .sync_c
+          .or_else(|| default.and_then(|d| d.sync_c))
+          .unwrap_or(true),
+        sync_s: project
+          .sync_s
+          .or_else(|| default.and_then(|d| d.sync_s))
+          .unwrap_or(false),
+        sync_tags: project
+          .sync_tags
+          .or_else(|| default.and_then(|d| d.sync_tags))
+          .unwrap_or(true),
         file_ops: project.file_operations.clone(),
         manifest_project: false,
+        sparse_checkout: sparse_checkout.filter(|patterns: &Vec<String>| !patterns.is_empty()),
       });
     }
     Ok(projects)
@@ -648,10 +1274,12 @@ impl Tree {
     checkout: CheckoutType,
     do_project_cleanup: bool,
     detach: bool,
+    rebase: bool,
     fetch_tags: bool,
     ssh_masters: &mut HashMap<String, std::process::Child>,
     no_lfs: bool,
   ) -> Result<i32, Error> {
+    let fetching = fetch_target.is_some();
     if let Some(target) = fetch_target {
       // The same underlying repository might be checked out into multiple directories.
       #[derive(PartialEq, Eq, Hash)]
@@ -659,7 +1287,12 @@ impl Tree {
         remote: String,
         project_name: String,
       }
-      let mut fetch_projects = HashMap::<FetchProject, FetchTarget>::new();
+      struct FetchOptions {
+        target: FetchTarget,
+        depth: Option<u32>,
+        tags: bool,
+      }
+      let mut fetch_projects = HashMap::<FetchProject, FetchOptions>::new();
       let mut remote_names = HashSet::new();
 
       for project in &projects {
@@ -667,11 +1300,24 @@ impl Tree {
           remote: project.remote.clone(),
           project_name: project.project_name.clone(),
         };
-        let local_target = target.clone().reify(&project.revision);
-        fetch_projects
-          .entry(key)
-          .or_insert_with(FetchTarget::empty)
-          .merge(&local_target);
+        let local_target = if target == FetchTarget::Upstream && !project.sync_c {
+          FetchTarget::All
+        } else {
+          target.clone().reify(&project.revision)
+        };
+        let options = fetch_projects.entry(key).or_insert_with(|| FetchOptions {
+          target: FetchTarget::empty(),
+          depth: project.clone_depth,
+          tags: false,
+        });
+        options.target.merge(&local_target);
+        options.tags |= fetch_tags && project.sync_tags;
+
+        // Fetch enough history to satisfy every checkout of the repository.
+        options.depth = match (options.depth, project.clone_depth) {
+          (Some(lhs), Some(rhs)) => Some(lhs.max(rhs)),
+          _ => None,
+        };
         remote_names.insert(project.remote.clone());
       }
 
@@ -701,10 +1347,12 @@ impl Tree {
       }
 
       let mut job = Job::with_name("fetching");
-      for (project, target) in &fetch_projects {
+      for (project, options) in &fetch_projects {
         let project_name = &project.project_name;
         let remote_name = &project.remote;
-        let target = target.clone();
+        let target = options.target.clone();
+        let depth = options.depth;
+        let fetch_tags = options.tags;
 
         job.add_task(project_name, move || -> Result<(), Error> {
           let remote = config
@@ -721,7 +1369,7 @@ impl Tree {
           };
           let target = target_vec.as_deref();
           depot
-            .fetch_repo(remote, project_name, target, fetch_tags, None)
+            .fetch_repo(remote, project_name, target, fetch_tags, depth)
             .with_context(|| format!("failed to fetch for project {}", project_name,))?;
           Ok(())
         });
@@ -736,166 +1384,264 @@ impl Tree {
       }
     }
 
+    let mut rc = 0;
     if checkout == CheckoutType::Checkout || checkout == CheckoutType::RefsOnly {
       let lfs_projects = dashmap::DashMap::<String, PathBuf>::with_capacity(10);
-      let mut job = Job::with_name("checkout");
+      let submodule_projects = dashmap::DashMap::<String, PathBuf>::new();
       let tree_root = &self.path;
 
+      // Projects can be nested inside of other projects, so check out projects in passes by depth, so that
+      // parents are always checked out before their children.
+      let project_paths: HashSet<&Path> = projects.iter().map(|p| Path::new(&p.project_path)).collect();
+      let mut passes: Vec<Vec<&ProjectInfo>> = Vec::new();
       for project in &projects {
-        let lfs_projects = &lfs_projects;
-        let project_path = tree_root.join(&project.project_path);
+        let depth = Path::new(&project.project_path)
+          .ancestors()
+          .skip(1)
+          .filter(|ancestor| project_paths.contains(ancestor))
+          .count();
+        if passes.len() <= depth {
+          passes.resize_with(depth + 1, Vec::new);
+        }
+        passes[depth].push(project);
+      }
 
-        job.add_task(&project.project_path, move || {
-          let remote = config
-            .find_remote(&project.remote)
-            .with_context(|| format!("failed to find remote {}", project.remote))?;
-          let depot = config
-            .find_depot(&remote.depot)
-            .with_context(|| format!("failed to find depot for remote {}", project.remote))?;
+      let mut rebase_conflicts = Vec::new();
+      let mut failed: HashSet<String> = HashSet::new();
+      for pass in passes {
+        let mut job = Job::with_name("checkout");
+        for project in pass {
+          // Checking out a child would create its parent's directory, which would then look like a broken repository.
+          if let Some(parent) = Path::new(&project.project_path)
+            .ancestors()
+            .skip(1)
+            .find(|ancestor| failed.contains(ancestor.to_str().unwrap_or_default()))
+          {
+            println!("{}", project_style().apply_to(&project.project_path));
+            println!(
+              "{}",
+              console::style(format!("  parent project {} failed to check out", parent.display())).red()
+            );
+            failed.insert(project.project_path.clone());
+            continue;
+          }
 
-          let project_name = &project.project_name;
-          let revision = &project.revision;
+          let lfs_projects = &lfs_projects;
+          let submodule_projects = &submodule_projects;
+          let project_path = tree_root.join(&project.project_path);
 
-          if project_path.exists() {
-            depot
-              .update_remote_refs(remote, &project.project_name, &project_path)
-              .context("failed to update remote refs")?;
-          }
+          job.add_task(&project.project_path, move || {
+            let remote = config
+              .find_remote(&project.remote)
+              .with_context(|| format!("failed to find remote {}", project.remote))?;
+            let depot = config
+              .find_depot(&remote.depot)
+              .with_context(|| format!("failed to find depot for remote {}", project.remote))?;
+
+            let project_name = &project.project_name;
+            let revision = &project.revision;
 
-          if checkout == CheckoutType::Checkout {
             if project_path.exists() {
-              let repo = git2::Repository::open(&project_path).context("failed to open repository".to_string())?;
-
-              // There's two things to be concerned about here:
-              //  - HEAD might be attached to a branch
-              //  - the repo might have uncommitted changes in the index or worktree
-              //
-              // If HEAD is attached to a branch, we choose to do nothing (for now), unless explicitly told to detach.
-              // At some point, we should probably try to perform the equivalent of `git pull --rebase`.
-              //
-              // If the repo has uncommitted changes, do a dry-run first, and give up if we have any conflicts.
-              let head_detached = repo.head_detached().context("failed to check if HEAD is detached")?;
-              let current_head = repo.head();
-              let current_head_oid = match current_head {
-                Ok(ref head) => Some(head.target().context("HEAD not a direct reference?")?),
-                Err(_) => None,
-              };
+              depot
+                .update_remote_refs(remote, &project.project_name, &project_path)
+                .context("failed to update remote refs")?;
+            }
 
-              let new_head = util::parse_revision(&repo, &remote.name, revision)
-                .with_context(|| {
-                  format!(
-                    "failed to find revision to sync to (wanted {}/{} in {:?})",
-                    remote.name, revision, project_path
-                  )
-                })?
-                .peel_to_commit()?;
-
-              if Some(new_head.id()) == current_head_oid {
-                // We're already at the top of tree.
-              } else {
-                if let Some(current_head_oid) = current_head_oid {
-                  if detach {
-                    repo
-                      .set_head_detached(current_head_oid)
-                      .context("failed to set HEAD detached")?;
-                  } else {
-                    // Check if the new head descends from the current one.
-                    if !repo
-                      .graph_descendant_of(new_head.id(), current_head_oid)
-                      .context("graph descendent of failed")?
-                    {
-                      let (ahead, behind) = repo
-                        .graph_ahead_behind(current_head_oid, new_head.id())
-                        .context("graph ahead behind failed")?;
-                      let head_name = if head_detached {
-                        console::style("no branch".to_string()).red().to_string()
-                      } else {
-                        let head = current_head.unwrap();
-                        let head_short = head.shorthand().context("branch name contains invalid UTF-8")?;
-                        format!("branch {}", branch_style().apply_to(&head_short))
-                      };
-                      bail!("{} {}", head_name, util::ahead_behind(ahead, behind));
+            let mut rebase_conflict = false;
+            if checkout == CheckoutType::Checkout {
+              if project_path.exists() {
+                let repo = git2::Repository::open(&project_path).context("failed to open repository".to_string())?;
+                let mut rebased = false;
+
+                util::set_sparse_checkout(&repo, project.sparse_checkout.as_deref(), true)
+                  .context("failed to update sparse checkout")?;
+
+                // There's two things to be concerned about here:
+                //  - HEAD might be attached to a branch
+                //  - the repo might have uncommitted changes in the index or worktree
+                //
+                // If HEAD is attached to a branch, we fast-forward it if possible. Otherwise, we give up, unless
+                // explicitly told to detach, or to rebase the branch (the equivalent of `git pull --rebase`).
+                //
+                // If the repo has uncommitted changes, do a dry-run first, and give up if we have any conflicts.
+                let head_detached = repo.head_detached().context("failed to check if HEAD is detached")?;
+                let current_head = repo.head();
+                let current_head_oid = match current_head {
+                  Ok(ref head) => Some(head.target().context("HEAD not a direct reference?")?),
+                  Err(_) => None,
+                };
+
+                let new_head = util::parse_revision(&repo, &remote.name, revision)
+                  .with_context(|| {
+                    format!(
+                      "failed to find revision to sync to (wanted {}/{} in {:?})",
+                      remote.name, revision, project_path
+                    )
+                  })?
+                  .peel_to_commit()?;
+
+                if Some(new_head.id()) == current_head_oid {
+                  // We're already at the top of tree.
+                } else {
+                  if let Some(current_head_oid) = current_head_oid {
+                    if detach {
+                      repo
+                        .set_head_detached(current_head_oid)
+                        .context("failed to set HEAD detached")?;
+                    } else {
+                      // Check if the new head descends from the current one.
+                      if !repo
+                        .graph_descendant_of(new_head.id(), current_head_oid)
+                        .context("graph descendent of failed")?
+                      {
+                        if rebase && !head_detached {
+                          rebase_conflict = Tree::rebase_onto(&repo, &project_path, &new_head)?;
+                          rebased = true;
+                        } else {
+                          let (ahead, behind) = repo
+                            .graph_ahead_behind(current_head_oid, new_head.id())
+                            .context("graph ahead behind failed")?;
+                          let head_name = if head_detached {
+                            console::style("no branch".to_string()).red().to_string()
+                          } else {
+                            let head = current_head.unwrap();
+                            let head_short = head.shorthand().context("branch name contains invalid UTF-8")?;
+                            format!("branch {}", branch_style().apply_to(&head_short))
+                          };
+                          bail!("{} {}", head_name, util::ahead_behind(ahead, behind));
+                        }
+                      }
+                    }
+                  }
+
+                  if !rebased && (util::is_partial_clone(&repo) || util::is_sparse_checkout(&repo)) {
+                    // libgit2 can neither fetch missing file contents nor respect sparse checkout, so let git do
+                    // the checkout. Like the dry run below, git refuses to overwrite dirty changes.
+                    let mut cmd = std::process::Command::new("git");
+                    cmd.current_dir(&project_path);
+                    if repo.head_detached().context("failed to check if HEAD is detached")? {
+                      cmd.arg("checkout").arg("-q").arg("--detach");
+                    } else {
+                      cmd.arg("merge").arg("-q").arg("--ff-only");
                     }
+                    let git_output = cmd
+                      .arg(new_head.id().to_string())
+                      .output()
+                      .context("failed to spawn git")?;
+                    if !git_output.status.success() {
+                      bail!(
+                        "failed to checkout to {:?}: {}",
+                        new_head,
+                        String::from_utf8_lossy(&git_output.stderr)
+                      );
+                    }
+                  } else if !rebased {
+                    // Do a dry run first to look for dirty changes.
+                    repo
+                      .checkout_tree(
+                        new_head.as_object(),
+                        Some(git2::build::CheckoutBuilder::new().dry_run()),
+                      )
+                      .with_context(|| format!("failed to dry run checkout to {:?}", new_head))?;
+
+                    repo
+                      .checkout_tree(new_head.as_object(), None)
+                      .with_context(|| format!("failed to checkout to {:?}", new_head))?;
+                    repo
+                      .reset(new_head.as_object(), git2::ResetType::Soft, None)
+                      .with_context(|| format!("failed to move HEAD to {:?}", new_head))?;
                   }
                 }
+              } else {
+                depot.clone_repo(
+                  remote,
+                  project_name,
+                  project.push_url.as_deref(),
+                  project.sparse_checkout.as_deref(),
+                  revision,
+                  &project_path,
+                )?;
+              }
 
-                // Do a dry run first to look for dirty changes.
+              if project.manifest_project {
+                // Some tools look at the upstream tracking branch of .repo/manifest to determine
+                // what manifest branch is being used.
+                let repo = git2::Repository::open(&project_path).context("failed to open repository".to_string())?;
+                let head = repo
+                  .head()
+                  .context("failed to get HEAD")?
+                  .peel_to_commit()
+                  .context("failed to peel HEAD to commit")?;
+
+                let mut branch = match repo.find_branch("default", git2::BranchType::Local) {
+                  Ok(branch) => branch,
+                  Err(_) => repo
+                    .branch("default", &head, true)
+                    .context("failed to create manifest default branch")?,
+                };
+
+                // TODO: repo uses origin as the upstream, regardless of what the remote is called.
+                branch
+                  .set_upstream(Some(&format!("{}/{}", project.remote, project.revision)))
+                  .with_context(|| {
+                    format!(
+                      "failed to set manifest branch upstream to {}/{}",
+                      project.remote, project.revision
+                    )
+                  })?;
                 repo
-                  .checkout_tree(
-                    new_head.as_object(),
-                    Some(git2::build::CheckoutBuilder::new().dry_run()),
-                  )
-                  .with_context(|| format!("failed to dry run checkout to {:?}", new_head))?;
+                  .set_head("refs/heads/default")
+                  .context("failed to set manifest HEAD")?;
+              }
 
-                repo
-                  .checkout_tree(new_head.as_object(), None)
-                  .with_context(|| format!("failed to checkout to {:?}", new_head))?;
-                repo
-                  .reset(new_head.as_object(), git2::ResetType::Soft, None)
-                  .with_context(|| format!("failed to move HEAD to {:?}", new_head))?;
+              // Set up symlinks to repo hooks.
+              let hooks_dir = project_path.join(".git").join("hooks");
+              let relpath = pathdiff::diff_paths(tree_root, &hooks_dir)
+                .ok_or_else(|| format_err!("failed to calculate path diff from hooks to tree root"))?
+                .join(".pore")
+                .join("hooks");
+              for filename in hooks::hooks().keys() {
+                let target = relpath.join(filename);
+                let symlink_path = hooks_dir.join(filename);
+                let _ = std::fs::remove_file(&symlink_path);
+                create_symlink(&target, &symlink_path)
+                  .with_context(|| format!("failed to create symlink at {:?}", &symlink_path))?;
               }
-            } else {
-              depot.clone_repo(remote, project_name, revision, &project_path)?;
-            }
 
-            if project.manifest_project {
-              // Some tools look at the upstream tracking branch of .repo/manifest to determine
-              // what manifest branch is being used.
-              let repo = git2::Repository::open(&project_path).context("failed to open repository".to_string())?;
-              let head = repo
-                .head()
-                .context("failed to get HEAD")?
-                .peel_to_commit()
-                .context("failed to peel HEAD to commit")?;
-
-              let mut branch = match repo.find_branch("default", git2::BranchType::Local) {
-                Ok(branch) => branch,
-                Err(_) => repo
-                  .branch("default", &head, true)
-                  .context("failed to create manifest default branch")?,
-              };
+              if fetching && project.sync_s && Path::exists(&project_path.join(".gitmodules")) {
+                submodule_projects.insert(project.project_path.clone(), project_path.clone());
+              }
 
-              // TODO: repo uses origin as the upstream, regardless of what the remote is called.
-              branch
-                .set_upstream(Some(&format!("{}/{}", project.remote, project.revision)))
-                .with_context(|| {
-                  format!(
-                    "failed to set manifest branch upstream to {}/{}",
-                    project.remote, project.revision
-                  )
-                })?;
-              repo
-                .set_head("refs/heads/default")
-                .context("failed to set manifest HEAD")?;
+              if !no_lfs && Path::exists(&project_path.join(".lfsconfig")) {
+                lfs_projects.insert(project.project_path.clone(), project_path);
+              }
             }
 
-            // Set up symlinks to repo hooks.
-            let hooks_dir = project_path.join(".git").join("hooks");
-            let relpath = pathdiff::diff_paths(tree_root, &hooks_dir)
-              .ok_or_else(|| format_err!("failed to calculate path diff from hooks to tree root"))?
-              .join(".pore")
-              .join("hooks");
-            for filename in hooks::hooks().keys() {
-              let target = relpath.join(filename);
-              let symlink_path = hooks_dir.join(filename);
-              let _ = std::fs::remove_file(&symlink_path);
-              create_symlink(&target, &symlink_path)
-                .with_context(|| format!("failed to create symlink at {:?}", &symlink_path))?;
-            }
+            Ok(rebase_conflict)
+          });
+        }
 
-            if !no_lfs && Path::exists(&project_path.join(".lfsconfig")) {
-              lfs_projects.insert(project.project_path.clone(), project_path);
-            }
-          }
+        let results = pool.execute(job);
+        for failure in &results.failed {
+          println!("{}", project_style().apply_to(&failure.name));
+          println!("{}", console::style(format!("  {}", failure.result)).red());
+          failed.insert(failure.name.clone());
+        }
 
-          Ok(())
-        });
+        rebase_conflicts.extend(results.successful.into_iter().filter(|r| r.result).map(|r| r.name));
       }
 
-      let results = pool.execute(job);
-      for failure in &results.failed {
-        println!("{}", project_style().apply_to(&failure.name));
-        println!("{}", console::style(format!("  {}", failure.result)).red());
+      if !rebase_conflicts.is_empty() {
+        rebase_conflicts.sort();
+        println!(
+          "failed to rebase {} project{}, resolve the conflicts and run `git rebase --continue`, or `git rebase --abort`:",
+          rebase_conflicts.len(),
+          if rebase_conflicts.len() == 1 { "" } else { "s" }
+        );
+        for conflict in &rebase_conflicts {
+          println!("  {}", project_style().clone().red().apply_to(conflict));
+        }
+        rc = 1;
       }
 
       // Perform linkfiles/copyfiles.
@@ -976,7 +1722,33 @@ impl Tree {
             eprintln!("warning: {}", e);
           }
         }
-        self.write_config().context("failed to write tree config")?;
+        self.write_config().context("failed to write tree config")?;
+      }
+
+      if !submodule_projects.is_empty() {
+        let mut job = Job::with_name("submodules");
+
+        for (project_name, project_path) in submodule_projects {
+          job.add_task(project_name, move || -> Result<(), Error> {
+            let output = std::process::Command::new("git")
+              .args(["submodule", "update", "--init", "--recursive"])
+              .current_dir(&project_path)
+              .output()
+              .context("failed to spawn git submodule update")?;
+            ensure!(
+              output.status.success(),
+              "git submodule update failed: {}",
+              String::from_utf8_lossy(&output.stderr)
+            );
+            Ok(())
+          });
+        }
+
+        let results = pool.execute(job);
+        for failure in &results.failed {
+          println!("{}", project_style().apply_to(&failure.name));
+          println!("{}", console::style(format!("  {}", failure.result)).red());
+        }
       }
 
       if !lfs_projects.is_empty() {
@@ -1011,7 +1783,27 @@ impl Tree {
       }
     }
 
-    Ok(0)
+    Ok(rc)
+  }
+
+  /// Rebase the current branch of a repository onto a new upstream commit.
+  ///
+  /// Returns whether the rebase stopped due to conflicts, in which case the rebase is left in progress.
+  fn rebase_onto(repo: &git2::Repository, project_path: &Path, upstream: &git2::Commit) -> Result<bool, Error> {
+    let output = std::process::Command::new("git")
+      .current_dir(project_path)
+      .arg("rebase")
+      .arg(upstream.id().to_string())
+      .output()
+      .context("failed to spawn git rebase")?;
+
+    if output.status.success() {
+      Ok(false)
+    } else if repo.state() != git2::RepositoryState::Clean {
+      Ok(true)
+    } else {
+      bail!("git rebase failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
   }
 
   fn write_hook(&self, directory: &Path, filename: &str, contents: &str) -> Result<(), Error> {
@@ -1041,8 +1833,9 @@ impl Tree {
   pub fn update_hooks(&self) -> Result<(), Error> {
     // Just always do this, since it's cheap.
     let hooks_dir = PathBuf::new().join(".pore").join("hooks");
+    let pore = std::env::current_exe().context("failed to find pore binary")?;
     for (filename, contents) in hooks::hooks() {
-      self.write_hook(hooks_dir.as_path(), filename, contents)?;
+      self.write_hook(hooks_dir.as_path(), filename, &hooks::render(contents, &pore))?;
     }
 
     Ok(())
@@ -1055,6 +1848,17 @@ impl Tree {
     create_symlink("../.pore/manifest", self.path.join(".repo").join("manifests"))?;
     create_symlink("../.pore/manifest.xml", self.path.join(".repo").join("manifest.xml"))?;
 
+    // Tools that drop files into .repo/local_manifests should end up writing to .pore/local_manifests. If it's
+    // already a directory, leave it alone, since read_manifest reads from it too.
+    let repo_local_manifests = self.path.join(".repo").join("local_manifests");
+    let is_real_dir = std::fs::symlink_metadata(&repo_local_manifests).map_or(false, |metadata| metadata.is_dir());
+    if !is_real_dir {
+      let local_manifests_path = self.path.join(".pore").join("local_manifests");
+      std::fs::create_dir_all(&local_manifests_path)
+        .with_context(|| format!("failed to create directory {:?}", local_manifests_path))?;
+      create_symlink("../.pore/local_manifests", repo_local_manifests)?;
+    }
+
     // Write a script that forwards repo to pore.
     let repo_bin_dir = PathBuf::new().join(".repo").join("repo");
     self.write_hook(
@@ -1144,6 +1948,7 @@ impl Tree {
     fetch_target: FetchTarget,
     checkout: CheckoutType,
     detach: bool,
+    rebase: bool,
     fetch_tags: bool,
     no_lfs: bool,
   ) -> Result<i32, Error> {
@@ -1156,6 +1961,7 @@ impl Tree {
       fetch_target,
       checkout,
       detach,
+      rebase,
       fetch_tags,
       &mut ssh_masters,
       no_lfs,
@@ -1181,6 +1987,7 @@ impl Tree {
     fetch_target: FetchTarget,
     checkout: CheckoutType,
     detach: bool,
+    rebase: bool,
     fetch_tags: bool,
     ssh_masters: &mut HashMap<String, std::process::Child>,
     no_lfs: bool,
@@ -1190,9 +1997,15 @@ impl Tree {
       project_path: ".pore/manifest".into(),
       project_name: self.config.manifest.clone(),
       remote: self.config.remote.clone(),
+      push_url: None,
       revision: self.config.branch.clone(),
+      clone_depth: None,
+      sync_c: true,
+      sync_s: false,
+      sync_tags: true,
       file_ops: Vec::new(),
       manifest_project: true,
+      sparse_checkout: None,
     }];
 
     if fetch_type != FetchType::NoFetch {
@@ -1204,6 +2017,7 @@ impl Tree {
         checkout,
         false,
         detach,
+        rebase,
         fetch_tags,
         ssh_masters,
         no_lfs,
@@ -1212,7 +2026,15 @@ impl Tree {
 
     let manifest = self.read_manifest()?;
     let projects = self.collect_manifest_projects(config, &manifest, sync_under.clone(), None)?;
-    self.sync_repos(
+
+    // Projects from other remotes might live in other depots, which also need to know about the tree.
+    let mut depots = BTreeSet::new();
+    depots.insert(config.find_remote(&self.config.remote)?.depot.clone());
+    for project in &projects {
+      depots.insert(config.find_remote(&project.remote)?.depot.clone());
+    }
+
+    let rc = self.sync_repos(
       pool,
       config,
       projects,
@@ -1224,6 +2046,7 @@ impl Tree {
       checkout,
       sync_under.is_none(),
       detach,
+      rebase,
       fetch_tags,
       ssh_masters,
       no_lfs || fetch_type == FetchType::NoFetch,
@@ -1234,7 +2057,11 @@ impl Tree {
       self.ensure_repo_compat()?;
     }
 
-    Ok(0)
+    for depot in depots {
+      config.find_depot(&depot)?.register_tree(&self.path)?;
+    }
+
+    Ok(rc)
   }
 
   pub fn branches(
@@ -1308,8 +2135,20 @@ impl Tree {
           .statuses(Some(git2::StatusOptions::new().include_untracked(true)))
           .with_context(|| format!("failed to get status of repository {}", project.project_path))?;
 
+        // libgit2 doesn't know about sparse checkout, and reports files outside of it as deleted.
+        let mut skipped = HashSet::new();
+        if util::is_sparse_checkout(&repo) {
+          let index = repo.index().context("failed to read index")?;
+          for entry in index.iter() {
+            if entry.flags_extended & git2::IndexEntryExtendedFlag::SKIP_WORKTREE.bits() != 0 {
+              skipped.insert(entry.path);
+            }
+          }
+        }
+
         let files: Vec<FileStatus> = statuses
           .iter()
+          .filter(|status| !(status.status() == git2::Status::WT_DELETED && skipped.contains(status.path_bytes())))
           .map(|status| {
             let filename = status.path().unwrap_or("???").to_string();
             let flags = status.status();
@@ -1372,6 +2211,86 @@ impl Tree {
     Ok(pool.execute(job))
   }
 
+  pub fn diff(
+    &self,
+    config: &Config,
+    pool: &mut Pool,
+    diff_under: Option<Vec<PathBuf>>,
+    cached: bool,
+    upstream: bool,
+    stat: bool,
+  ) -> Result<i32, Error> {
+    let manifest = self.read_manifest()?;
+    let projects = self.collect_manifest_projects(config, &manifest, diff_under, None)?;
+
+    let mut job = Job::with_name("diff");
+    for project in &projects {
+      job.add_task(&project.project_path, move || -> Result<Vec<u8>, Error> {
+        let path = self.path.join(&project.project_path);
+        let repo = git2::Repository::open(&path)
+          .with_context(|| format!("failed to open repository {}", project.project_path))?;
+
+        // Prefix paths with the project path, so that they're relative to the root of the tree.
+        let mut opts = git2::DiffOptions::new();
+        opts
+          .old_prefix(format!("a/{}/", project.project_path))
+          .new_prefix(format!("b/{}/", project.project_path));
+
+        let diff = if upstream {
+          let head = repo
+            .head()
+            .context("failed to get HEAD")?
+            .peel_to_commit()
+            .context("failed to peel HEAD to commit")?;
+          let upstream_commit = util::parse_revision(&repo, &project.remote, &project.revision)?
+            .peel_to_commit()
+            .context("failed to peel upstream object to commit")?;
+
+          // Equivalent to `git diff upstream...HEAD`.
+          let merge_base = repo
+            .merge_base(head.id(), upstream_commit.id())
+            .context("failed to find merge base with upstream")?;
+          let base_tree = repo.find_commit(merge_base)?.tree()?;
+          repo.diff_tree_to_tree(Some(&base_tree), Some(&head.tree()?), Some(&mut opts))
+        } else if cached {
+          let head_tree = repo
+            .head()
+            .context("failed to get HEAD")?
+            .peel_to_tree()
+            .context("failed to peel HEAD to tree")?;
+          repo.diff_tree_to_index(Some(&head_tree), None, Some(&mut opts))
+        } else {
+          repo.diff_index_to_workdir(None, Some(&mut opts))
+        }
+        .context("failed to calculate diff")?;
+
+        if stat {
+          render_diff_stat(&diff, &project.project_path)
+        } else {
+          render_diff_patch(&diff)
+        }
+      });
+    }
+
+    let results = pool.execute(job);
+    let mut successful = results.successful;
+    sort_by_project_order(&mut successful, &projects);
+
+    let mut stdout = std::io::stdout();
+    for result in successful {
+      stdout.write_all(&result.result)?;
+    }
+
+    if results.failed.is_empty() {
+      Ok(0)
+    } else {
+      for error in results.failed {
+        eprintln!("{}: {}", error.name, error.result);
+      }
+      Ok(1)
+    }
+  }
+
   pub fn start(
     &self,
     config: &Config,
@@ -1435,34 +2354,30 @@ impl Tree {
     ccs: &[String],
     private: bool,
     wip: bool,
+    ready: bool,
+    edit: bool,
     branch_name_as_topic: bool,
+    topic: Option<&str>,
+    hashtags: &[String],
+    labels: &[String],
+    notify: Option<&str>,
+    notify_to: &[String],
     autosubmit: bool,
     presubmit_ready: bool,
     ps_description: Option<&str>,
+    dest: Option<&str>,
     dry_run: bool,
   ) -> Result<i32, Error> {
-    // TODO: Figure out how 0 (all projects) should work.
-    ensure!(
-      upload_under.as_ref().map_or(false, |v| !v.is_empty()),
-      "pathless upload not yet implemented; must specify which projects to upload"
-    );
-
-    ensure!(
-      current_branch,
-      "interactive workflow not yet implemented; --cbr is required"
-    );
-
-    if !no_verify {
-      let result = self.preupload(config, pool, upload_under.clone())?;
-      if result != 0 {
-        bail!("preupload hooks failed");
-      }
-    }
+    // Be strict about projects that were explicitly asked for, but quietly skip anything else with nothing to upload.
+    let explicit = upload_under.as_ref().map_or(false, |v| !v.is_empty());
 
     struct UploadInfo {
-      project_path: String,
+      name: String,
       summary: UploadSummary,
       command: std::process::Command,
+      branch: String,
+      upstream: git2::Oid,
+      missing_change_ids: Vec<git2::Oid>,
     }
 
     let manifest = self.read_manifest()?;
@@ -1473,8 +2388,27 @@ impl Tree {
       let repo = git2::Repository::open(self.path.join(&project.project_path))
         .context("failed to open repository".to_string())?;
 
-      if repo.head_detached().context("failed to check if HEAD is detached")? {
-        bail!("cannot upload from detached HEAD")
+      let src_branches = if current_branch {
+        if repo.head_detached().context("failed to check if HEAD is detached")? {
+          ensure!(!explicit, "cannot upload from detached HEAD");
+          continue;
+        }
+        let head = repo.head().context("could not determine HEAD")?;
+        vec![BranchInfo::from_ref(head)?]
+      } else {
+        let mut branches = Vec::new();
+        for branch in repo
+          .branches(Some(git2::BranchType::Local))
+          .context("failed to list branches")?
+        {
+          let (branch, _) = branch.context("failed to read branch")?;
+          branches.push(BranchInfo::from_branch(branch)?);
+        }
+        branches
+      };
+
+      if src_branches.is_empty() {
+        continue;
       }
 
       let project_meta = manifest
@@ -1482,78 +2416,212 @@ impl Tree {
         .get(&PathBuf::from(&project.project_path))
         .ok_or_else(|| format_err!("failed to find project {:?}", project.project_path))?;
 
-      // TODO: Obey <remote review="...">?
       let remote_name = project_meta.find_remote(&manifest)?;
-      let dest_branch_name = project_meta.find_dest_branch(&manifest)?;
+      let dest_branch_name = match dest {
+        Some(dest) => dest.to_string(),
+        None => project_meta.find_dest_branch(&manifest)?,
+      };
 
       let remote_config = config.find_remote(&remote_name)?;
+      let reviewers = remote_config.resolve_users(reviewers)?;
+      let ccs = remote_config.resolve_users(ccs)?;
+      let notify_to = remote_config.resolve_users(notify_to)?;
 
-      let head = repo.head().context("could not determine HEAD")?;
-      let src_branch_info = BranchInfo::from_ref(head)?;
       let dest_branch_info = BranchInfo::from_branch_name(
         &repo,
         format!("{}/{}", remote_name, dest_branch_name).as_str(),
         git2::BranchType::Remote,
       )?;
 
-      let commits = util::find_independent_commits(&repo, &src_branch_info.commit, &dest_branch_info.commit)?;
-      if commits.is_empty() {
-        bail!(format!("No commits to upload for {}", &project.project_path));
-      }
+      // Push to the URL specified by the manifest's <remote review="..." pushurl="...">, falling back to
+      // whatever the checkout's remote is configured to push to.
+      let push_url = match manifest
+        .remotes
+        .get(&remote_name)
+        .and_then(|remote| remote.push_url(&project_meta.name))
+      {
+        Some(push_url) => push_url,
+        None => {
+          let remote = repo
+            .find_remote(&remote_name)
+            .with_context(|| format!("failed to find remote {} in {}", remote_name, project.project_path))?;
+          remote
+            .pushurl()
+            .or_else(|| remote.url())
+            .ok_or_else(|| format_err!("remote {} has no URL", remote_name))?
+            .to_string()
+        }
+      };
 
-      let summary = summarize_upload(
-        &project.project_path,
-        &repo,
-        &src_branch_info,
-        &dest_branch_info,
-        &remote_name,
-        &commits,
-      )?;
+      for src_branch_info in src_branches {
+        let commits = util::find_independent_commits(&repo, &src_branch_info.commit, &dest_branch_info.commit)?;
+        if commits.is_empty() {
+          ensure!(
+            !(explicit && current_branch),
+            "No commits to upload for {}",
+            &project.project_path
+          );
+          continue;
+        }
 
-      let upload_options = remote_config.default_upload_options.clone();
-      let cmd = util::make_push_command(
-        self.path.join(&project.project_path),
-        &remote_name,
-        dest_branch_info.name_without_remote(),
-        &util::UploadOptions {
-          ccs,
-          reviewers,
-          topic: if branch_name_as_topic {
-            Some(src_branch_info.name)
-          } else {
-            None
+        let upload_options = util::UploadOptions {
+          ccs: &ccs,
+          reviewers: &reviewers,
+          topic: match topic {
+            Some(topic) => Some(topic.to_string()),
+            None if branch_name_as_topic => Some(src_branch_info.name.clone()),
+            None => None,
           },
+          hashtags,
+          labels,
+          notify,
+          notify_to: &notify_to,
           autosubmit,
           presubmit_ready,
           private,
           wip,
+          ready,
+          edit,
           ps_description,
-          upload_options,
-        },
+          upload_options: remote_config.default_upload_options.clone(),
+        };
+
+        let summary = summarize_upload(
+          &project.project_path,
+          &repo,
+          &src_branch_info,
+          &dest_branch_info,
+          &remote_name,
+          &push_url,
+          upload_options.push_options(),
+          &commits,
+        )?;
+
+        let name = format!("{} ({})", project.project_path, src_branch_info.name);
+        let cmd = util::make_push_command(
+          self.path.join(&project.project_path),
+          &push_url,
+          &src_branch_info.name,
+          dest_branch_info.name_without_remote(),
+          &upload_options,
+        );
+
+        uploads.push(UploadInfo {
+          name,
+          summary,
+          command: cmd,
+          branch: src_branch_info.name.clone(),
+          upstream: dest_branch_info.commit.id(),
+          missing_change_ids: util::find_commits_without_change_id(&repo, &commits)?,
+        })
+      }
+    }
+
+    ensure!(!uploads.is_empty(), "no branches to upload");
+
+    // Like repo, only bring up the editor if there's a choice to be made.
+    let uploads: Vec<UploadInfo> = if current_branch || uploads.len() == 1 {
+      confirm_upload(uploads.iter().map(|u| &u.summary).collect(), autosubmit)?;
+      uploads
+    } else {
+      let selected = select_uploads(
+        &self.path.join(".pore").join("UPLOAD_EDITMSG"),
+        uploads.iter().map(|u| &u.summary).collect(),
+        autosubmit,
+      )?;
+      uploads
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| selected.contains(i))
+        .map(|(_, upload)| upload)
+        .collect()
+    };
+
+    // Gerrit rejects commits without a Change-Id, so catch them before pushing anything.
+    let missing: Vec<&UploadInfo> = uploads.iter().filter(|u| !u.missing_change_ids.is_empty()).collect();
+    if !missing.is_empty() {
+      for upload in &missing {
+        eprintln!("{}: commits without a Change-Id:", upload.name);
+        for commit_summary in &upload.summary.commit_summaries {
+          if upload.missing_change_ids.contains(&commit_summary.id) {
+            eprintln!(
+              "  {:.10} {}",
+              console::style(commit_summary.id).cyan(),
+              commit_summary.summary
+            );
+          }
+        }
+      }
+
+      ensure!(!dry_run, "refusing to upload commits without a Change-Id");
+
+      eprint!(
+        "Add Change-Ids by rewriting {}? [y/N]? ",
+        if missing.len() == 1 {
+          "this branch"
+        } else {
+          "these branches"
+        }
       );
+      std::io::stderr().flush()?;
+      match util::read_line()?.as_str() {
+        "y" | "Y" => {}
+        _ => bail!("refusing to upload commits without a Change-Id"),
+      }
 
-      uploads.push(UploadInfo {
-        project_path: project.project_path.clone(),
-        summary,
-        command: cmd,
-      })
+      let mut still_missing = Vec::new();
+      for upload in &missing {
+        let repo = git2::Repository::open(self.path.join(&upload.summary.project_path))
+          .context("failed to open repository".to_string())?;
+        util::add_change_ids(&repo, &upload.branch, upload.upstream)
+          .with_context(|| format!("failed to add Change-Ids to {}", upload.name))?;
+
+        // The hook skips some commits, e.g. fixups, so check again.
+        let commits = find_branch_commits(&repo, &upload.branch, upload.upstream)?;
+        if !util::find_commits_without_change_id(&repo, &commits)?.is_empty() {
+          still_missing.push(upload.name.clone());
+        }
+      }
+
+      ensure!(
+        still_missing.is_empty(),
+        "refusing to upload commits without a Change-Id: {}",
+        still_missing.join(", ")
+      );
+      eprintln!();
     }
 
+    // Only check what's actually going to be uploaded, after any rewriting.
     if !no_verify {
-      // Separate the upload prompt from preupload hook output.
+      let mut targets = Vec::new();
+      for upload in &uploads {
+        let repo = git2::Repository::open(self.path.join(&upload.summary.project_path))
+          .context("failed to open repository".to_string())?;
+        targets.push(PreuploadTarget {
+          name: upload.name.clone(),
+          project_path: PathBuf::from(&upload.summary.project_path),
+          commits: PreuploadCommits::Explicit(find_branch_commits(&repo, &upload.branch, upload.upstream)?),
+        });
+      }
+
+      let result = self.run_preupload_hooks(&manifest, pool, targets)?;
+      if result != 0 {
+        bail!("preupload hooks failed");
+      }
+
+      // Separate preupload hook output from gerrit's.
       println!();
     }
 
-    confirm_upload(uploads.iter().map(|u| &u.summary).collect(), autosubmit)?;
-
     let mut job = Job::with_name("uploading");
     for UploadInfo {
-      project_path,
+      name,
       summary: _,
       mut command,
+      ..
     } in uploads
     {
-      job.add_task(project_path, move || -> Result<String, Error> {
+      job.add_task(name, move || -> Result<String, Error> {
         if dry_run {
           Ok(format!("running: {:?}", command))
         } else {
@@ -1678,6 +2746,104 @@ impl Tree {
     }
   }
 
+  /// Delete a topic branch (or all local branches, if `branch` is None) in every project.
+  pub fn abandon(
+    &self,
+    config: &Config,
+    pool: &mut Pool,
+    branch: Option<&str>,
+    abandon_under: Option<Vec<PathBuf>>,
+  ) -> Result<i32, Error> {
+    let manifest = self.read_manifest()?;
+    let projects = self.collect_manifest_projects(config, &manifest, abandon_under, None)?;
+
+    let mut job = Job::with_name("abandoning");
+
+    struct AbandonResult {
+      abandoned_branches: Vec<String>,
+    }
+
+    for project in &projects {
+      job.add_task(&project.project_path, move || -> Result<AbandonResult, Error> {
+        let path = self.path.join(&project.project_path);
+        let repo = git2::Repository::open(&path)
+          .with_context(|| format!("failed to open repository {:?}", project.project_path))?;
+
+        let mut detach = false;
+        let mut abandoned = Vec::new();
+        for local_branch in repo.branches(Some(git2::BranchType::Local))? {
+          let (local_branch, _) = local_branch?;
+          let branch_name = local_branch
+            .name()?
+            .ok_or_else(|| format_err!("branch has name with invalid UTF-8"))?
+            .to_string();
+          if branch.map_or(true, |branch| branch == branch_name) {
+            detach |= local_branch.is_head();
+            abandoned.push(branch_name);
+          }
+        }
+
+        // Branches can't be deleted while they're checked out, so detach to the manifest revision first.
+        if detach {
+          let commit = util::parse_revision(&repo, &project.remote, &project.revision)?
+            .peel_to_commit()
+            .context("failed to peel upstream object to commit")?;
+          repo
+            .checkout_tree(commit.as_object(), None)
+            .with_context(|| format!("failed to checkout {}", commit.id()))?;
+          repo
+            .set_head_detached(commit.id())
+            .context("failed to set HEAD detached")?;
+        }
+
+        for branch_name in &abandoned {
+          repo
+            .find_branch(branch_name, git2::BranchType::Local)?
+            .delete()
+            .with_context(|| format!("failed to delete branch {}", branch_name))?;
+        }
+
+        Ok(AbandonResult {
+          abandoned_branches: abandoned,
+        })
+      });
+    }
+
+    let results = pool.execute(job);
+    let mut failed = false;
+    for error in results.failed {
+      eprintln!("{}: {}", error.name, error.result);
+      failed = true;
+    }
+
+    let mut successful = results.successful;
+    sort_by_project_order(&mut successful, &projects);
+
+    let mut found = false;
+    for result in successful {
+      if !result.result.abandoned_branches.is_empty() {
+        found = true;
+        println!("{}", project_style().apply_to(result.name));
+        for branch in result.result.abandoned_branches {
+          println!("  {}", console::style(branch).red());
+        }
+      }
+    }
+
+    if let Some(branch) = branch {
+      if !found {
+        eprintln!("error: no project has branch {}", branch);
+        failed = true;
+      }
+    }
+
+    if !failed {
+      Ok(0)
+    } else {
+      Ok(1)
+    }
+  }
+
   pub fn rebase(
     &self,
     config: &Config,
@@ -1899,16 +3065,153 @@ impl Tree {
     Ok(rc)
   }
 
+  pub fn grep(
+    &self,
+    config: &Config,
+    pool: &mut Pool,
+    grep_under: Option<Vec<PathBuf>>,
+    group_filters: Option<Vec<GroupFilter>>,
+    pattern: &str,
+    ignore_case: bool,
+  ) -> Result<i32, Error> {
+    let regex = regex::bytes::RegexBuilder::new(pattern)
+      .case_insensitive(ignore_case)
+      .build()
+      .with_context(|| format!("invalid pattern '{}'", pattern))?;
+
+    let manifest = self.read_manifest()?;
+    let paths = self.relative_paths(grep_under.as_deref().unwrap_or_default())?;
+    let projects = self.collect_manifest_projects(config, &manifest, grep_under, group_filters)?;
+
+    struct GrepResult {
+      matches: usize,
+      output: Vec<u8>,
+    }
+
+    let mut job = Job::with_name("grep");
+    for project in &projects {
+      let regex = &regex;
+
+      // Only search the parts of the project that were asked for, if a path inside of it was requested.
+      let subpaths: Vec<PathBuf> = paths
+        .iter()
+        .filter_map(|path| path.strip_prefix(&project.project_path).ok())
+        .map(Path::to_path_buf)
+        .collect();
+
+      job.add_task(&project.project_path, move || -> Result<GrepResult, Error> {
+        let path = self.path.join(&project.project_path);
+        let repo = git2::Repository::open(&path)
+          .with_context(|| format!("failed to open repository {}", project.project_path))?;
+        let index = repo
+          .index()
+          .with_context(|| format!("failed to read index of repository {}", project.project_path))?;
+
+        let mut result = GrepResult {
+          matches: 0,
+          output: Vec::new(),
+        };
+
+        for entry in index.iter() {
+          // Only search regular files, skipping symlinks and submodules.
+          if entry.mode & 0o170000 != 0o100000 {
+            continue;
+          }
+
+          let file_path = String::from_utf8_lossy(&entry.path);
+          if !subpaths.is_empty()
+            && !subpaths
+              .iter()
+              .any(|subpath| Path::new(&*file_path).starts_with(subpath))
+          {
+            continue;
+          }
+
+          let data = match std::fs::read(path.join(&*file_path)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err).with_context(|| format!("failed to read {}", file_path)),
+          };
+
+          // Skip binary files, using the same heuristic as git.
+          if data.iter().take(8000).any(|&c| c == 0) {
+            continue;
+          }
+
+          let display_path = Path::new(&project.project_path).join(&*file_path);
+          for (line_number, line) in data.split(|&c| c == b'\n').enumerate() {
+            if regex.is_match(line) {
+              result.matches += 1;
+              writeln!(
+                result.output,
+                "{}{}{}{}{}",
+                console::style(display_path.display()).magenta(),
+                console::style(":").cyan(),
+                console::style(line_number + 1).green(),
+                console::style(":").cyan(),
+                String::from_utf8_lossy(line)
+              )?;
+            }
+          }
+        }
+
+        Ok(result)
+      });
+    }
+
+    let results = pool.execute(job);
+    let mut successful = results.successful;
+    sort_by_project_order(&mut successful, &projects);
+
+    let mut matches = 0;
+    let mut stdout = std::io::stdout();
+    for result in successful {
+      matches += result.result.matches;
+      stdout.write_all(&result.result.output)?;
+    }
+
+    if !results.failed.is_empty() {
+      for error in results.failed {
+        eprintln!("{}: {}", console::style(error.name).red().bold(), error.result);
+      }
+      return Ok(1);
+    }
+
+    // Like grep, fail if nothing matched.
+    Ok(if matches == 0 { 1 } else { 0 })
+  }
+
   pub fn preupload(&self, config: &Config, pool: &mut Pool, under: Option<Vec<PathBuf>>) -> Result<i32, Error> {
     let manifest = self.read_manifest().context("failed to read manifest")?;
     let projects = self
       .collect_manifest_projects(config, &manifest, under, None)
       .context("failed to collect manifest projects")?;
 
-    let hook_project_name = match manifest.repo_hooks {
+    let targets = projects
+      .iter()
+      .map(|project| PreuploadTarget {
+        name: project.project_path.clone(),
+        project_path: PathBuf::from(&project.project_path),
+        commits: PreuploadCommits::Head {
+          remote: &project.remote,
+          revision: &project.revision,
+        },
+      })
+      .collect();
+    self.run_preupload_hooks(&manifest, pool, targets)
+  }
+
+  /// Run repo's preupload hooks on the given commits.
+  fn run_preupload_hooks(
+    &self,
+    manifest: &Manifest,
+    pool: &mut Pool,
+    targets: Vec<PreuploadTarget>,
+  ) -> Result<i32, Error> {
+    let hook_project_name = match &manifest.repo_hooks {
       Some(hooks) => {
         // TODO: Bail out if pre-upload isn't in enabled-list.
-        match hooks.in_project {
+        match &hooks.in_project {
           Some(project) => project,
           None => return Ok(0),
         }
@@ -1927,7 +3230,7 @@ impl Tree {
 
     let mut hook_project = None;
     for project in manifest.projects.values() {
-      if project.name == hook_project_name {
+      if &project.name == hook_project_name {
         hook_project = Some(project);
         break;
       }
@@ -1947,35 +3250,47 @@ impl Tree {
       output: Vec<u8>,
     }
 
-    for project in &projects {
-      let project_path = self.path.join(&project.project_path);
+    for PreuploadTarget {
+      name,
+      project_path,
+      commits,
+    } in targets
+    {
+      let project_path = self.path.join(&project_path);
       let hook_path = &hook_path;
 
-      job.add_task(&project.project_path, move || -> Result<PresubmitResult, Error> {
-        let repo = git2::Repository::open(project_path.deref()).context("failed to open repository".to_string())?;
+      job.add_task(name, move || -> Result<PresubmitResult, Error> {
+        let commits = match commits {
+          PreuploadCommits::Head { remote, revision } => {
+            let repo = git2::Repository::open(project_path.deref()).context("failed to open repository".to_string())?;
+
+            let head = repo.head().context("could not determine HEAD")?;
+            let head_branch = git2::Branch::wrap(head);
+            if head_branch.name().is_err() {
+              // repo-hooks need to be on a branch.
+              return Ok(PresubmitResult {
+                rc: 0,
+                output: Vec::new(),
+              });
+            }
 
-        let head = repo.head().context("could not determine HEAD")?;
-        let head_branch = git2::Branch::wrap(head);
-        if head_branch.name().is_err() {
-          // repo-hooks need to be on a branch.
-          return Ok(PresubmitResult {
-            rc: 0,
-            output: Vec::new(),
-          });
-        }
+            let current_head = repo
+              .head()
+              .context("failed to get HEAD")?
+              .peel_to_commit()
+              .context("failed to peel HEAD to commit")?;
 
-        let current_head = repo
-          .head()
-          .context("failed to get HEAD")?
-          .peel_to_commit()
-          .context("failed to peel HEAD to commit")?;
+            let upstream_object = util::parse_revision(&repo, remote, revision)?;
+            let upstream_commit = upstream_object
+              .peel_to_commit()
+              .context("failed to peel upstream object to commit")?;
 
-        let upstream_object = util::parse_revision(&repo, &project.remote, &project.revision)?;
-        let upstream_commit = upstream_object
-          .peel_to_commit()
-          .context("failed to peel upstream object to commit")?;
+            util::find_independent_commits(&repo, &current_head, &upstream_commit)?
+          }
+
+          PreuploadCommits::Explicit(commits) => commits,
+        };
 
-        let commits = util::find_independent_commits(&repo, &current_head, &upstream_commit)?;
         if commits.is_empty() {
           Ok(PresubmitResult {
             rc: 0,
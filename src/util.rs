@@ -14,12 +14,15 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io;
 use std::path::Path;
 
 use anyhow::{Context, Error};
 
+use crate::hooks::commit_msg;
+
 pub fn assert_empty_directory<T: AsRef<Path> + Debug>(directory_path: T) -> Result<(), Error> {
   match std::fs::read_dir(&directory_path) {
     Ok(dir) => {
@@ -115,59 +118,101 @@ pub struct UploadOptions<'a> {
   pub ccs: &'a [String],
   pub reviewers: &'a [String],
   pub topic: Option<String>,
+  pub hashtags: &'a [String],
+  pub labels: &'a [String],
+  pub notify: Option<&'a str>,
+  pub notify_to: &'a [String],
   pub autosubmit: bool,
   pub presubmit_ready: bool,
   pub private: bool,
   pub wip: bool,
+  pub ready: bool,
+  pub edit: bool,
   pub ps_description: Option<&'a str>,
   pub upload_options: Vec<String>,
 }
 
-pub fn make_push_command(
-  project_path: std::path::PathBuf,
-  remote: &str,
-  dest_branch_name: &str,
-  options: &UploadOptions,
-) -> std::process::Command {
-  // https://gerrit-review.googlesource.com/Documentation/user-upload.html#push_options
-  // git push $REMOTE HEAD:refs/for/$UPSTREAM_BRANCH%$OPTIONS
-  let ref_spec = format!("HEAD:refs/for/{}", dest_branch_name);
-  let mut cmd = std::process::Command::new("git");
-  cmd.current_dir(project_path).arg("push");
+impl UploadOptions<'_> {
+  /// Get the Gerrit push options corresponding to the upload options.
+  ///
+  /// See https://gerrit-review.googlesource.com/Documentation/user-upload.html#push_options
+  pub fn push_options(&self) -> Vec<String> {
+    let mut result = Vec::new();
+    for reviewer in self.reviewers {
+      result.push(format!("r={}", reviewer));
+    }
 
-  for reviewer in options.reviewers {
-    cmd.arg("-o").arg(format!("r={}", reviewer));
-  }
+    for cc in self.ccs {
+      result.push(format!("cc={}", cc));
+    }
 
-  for cc in options.ccs {
-    cmd.arg("-o").arg(format!("cc={}", cc));
-  }
+    if self.wip {
+      result.push("wip".into());
+    }
 
-  if options.wip {
-    cmd.arg("-o").arg("wip");
-  }
+    if self.ready {
+      result.push("ready".into());
+    }
 
-  if options.private {
-    cmd.arg("-o").arg("private");
-  }
+    if self.private {
+      result.push("private".into());
+    }
 
-  if options.autosubmit {
-    cmd.arg("-o").arg("l=Autosubmit");
-  }
+    if self.edit {
+      result.push("edit".into());
+    }
 
-  if options.presubmit_ready {
-    cmd.arg("-o").arg("l=Presubmit-Ready");
-  }
+    if self.autosubmit {
+      result.push("l=Autosubmit".into());
+    }
 
-  if let Some(t) = &options.topic {
-    cmd.arg("-o").arg(format!("topic={}", t));
-  }
+    if self.presubmit_ready {
+      result.push("l=Presubmit-Ready".into());
+    }
+
+    for label in self.labels {
+      result.push(format!("l={}", label));
+    }
+
+    if let Some(t) = &self.topic {
+      result.push(format!("topic={}", t));
+    }
+
+    for hashtag in self.hashtags {
+      result.push(format!("hashtag={}", hashtag));
+    }
+
+    if let Some(notify) = self.notify {
+      result.push(format!("notify={}", notify));
+    }
 
-  if let Some(m) = &options.ps_description {
-    cmd.arg("-o").arg(format!("m={}", m));
+    for notify_to in self.notify_to {
+      result.push(format!("notify-to={}", notify_to));
+    }
+
+    if let Some(m) = &self.ps_description {
+      result.push(format!("m={}", m));
+    }
+
+    result.extend(self.upload_options.iter().cloned());
+    result
   }
+}
+
+pub fn make_push_command(
+  project_path: std::path::PathBuf,
+  remote: &str,
+  src_branch_name: &str,
+  dest_branch_name: &str,
+  options: &UploadOptions,
+) -> std::process::Command {
+  // https://gerrit-review.googlesource.com/Documentation/user-upload.html#push_options
+  // git push $REMOTE refs/heads/$BRANCH:refs/for/$UPSTREAM_BRANCH%$OPTIONS
+  let ref_spec = format!("refs/heads/{}:refs/for/{}", src_branch_name, dest_branch_name);
+  let mut cmd = std::process::Command::new("git");
+  cmd.current_dir(project_path).arg("push");
 
-  for option in &options.upload_options {
+  for option in options.push_options() {
     cmd.arg("-o").arg(option);
   }
 
@@ -189,6 +234,81 @@ pub fn find_independent_commits(
   Ok(revwalk.collect::<Result<Vec<_>, _>>()?)
 }
 
+/// Find the commits whose messages lack a Change-Id, which Gerrit will refuse.
+pub fn find_commits_without_change_id(repo: &git2::Repository, commits: &[git2::Oid]) -> Result<Vec<git2::Oid>, Error> {
+  let mut result = Vec::new();
+  for oid in commits {
+    let commit = repo.find_commit(*oid)?;
+    if !commit_msg::has_change_id(&String::from_utf8_lossy(commit.message_raw_bytes())) {
+      result.push(*oid);
+    }
+  }
+  Ok(result)
+}
+
+/// Rewrite the commits on a branch that aren't in upstream to add Change-Ids, like the commit-msg hook would have.
+pub fn add_change_ids(repo: &git2::Repository, branch_name: &str, upstream: git2::Oid) -> Result<(), Error> {
+  let refname = format!("refs/heads/{}", branch_name);
+  let old_tip = repo
+    .refname_to_id(&refname)
+    .with_context(|| format!("failed to find branch {}", branch_name))?;
+  let config = repo.config().context("failed to read git config")?;
+
+  let mut revwalk = repo.revwalk()?;
+  revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+  revwalk.hide(upstream)?;
+  revwalk.push(old_tip)?;
+
+  let mut rewritten: HashMap<git2::Oid, git2::Oid> = HashMap::new();
+  for oid in revwalk {
+    let oid = oid?;
+    let commit = repo.find_commit(oid)?;
+    let parents = commit
+      .parent_ids()
+      .map(|id| repo.find_commit(*rewritten.get(&id).unwrap_or(&id)))
+      .collect::<Result<Vec<_>, _>>()?;
+    let parents_changed = parents
+      .iter()
+      .zip(commit.parent_ids())
+      .any(|(parent, id)| parent.id() != id);
+
+    let message = String::from_utf8_lossy(commit.message_raw_bytes()).to_string();
+    let new_message = commit_msg::add_change_id(
+      &config,
+      &message,
+      commit.tree_id(),
+      commit.parent_id(0).ok(),
+      &commit_msg::format_ident(&commit.author()),
+      &commit_msg::format_ident(&commit.committer()),
+    )?;
+
+    if new_message.is_none() && !parents_changed {
+      continue;
+    }
+
+    let new_oid = repo
+      .commit(
+        None,
+        &commit.author(),
+        &commit.committer(),
+        new_message.as_deref().unwrap_or(&message),
+        &commit.tree()?,
+        &parents.iter().collect::<Vec<_>>(),
+      )
+      .with_context(|| format!("failed to rewrite commit {}", oid))?;
+    rewritten.insert(oid, new_oid);
+  }
+
+  if let Some(new_tip) = rewritten.get(&old_tip) {
+    // The trees are unchanged, so this is safe to do to a checked out branch as well.
+    repo
+      .reference_matching(&refname, *new_tip, true, old_tip, "pore: add Change-Ids")
+      .with_context(|| format!("failed to update branch {}", branch_name))?;
+  }
+
+  Ok(())
+}
+
 pub fn read_line() -> Result<String, Error> {
   let mut line = String::new();
   std::io::stdin().read_line(&mut line)?;
@@ -198,6 +318,33 @@ pub fn read_line() -> Result<String, Error> {
   Ok(line)
 }
 
+/// Let the user edit a file in the editor that git is configured to use, and return its new contents.
+pub fn edit_file(path: &Path, contents: &str) -> Result<String, Error> {
+  std::fs::write(path, contents).with_context(|| format!("failed to write {:?}", path))?;
+
+  let git_output = std::process::Command::new("git")
+    .arg("var")
+    .arg("GIT_EDITOR")
+    .output()
+    .context("failed to spawn git var")?;
+  ensure!(git_output.status.success(), "failed to determine editor");
+  let editor = String::from_utf8_lossy(&git_output.stdout).trim().to_string();
+
+  // Like git, let the shell interpret the editor, which might contain arguments.
+  let status = std::process::Command::new("sh")
+    .arg("-c")
+    .arg(format!("{} \"$@\"", editor))
+    .arg(&editor)
+    .arg(path)
+    .status()
+    .context("failed to spawn editor")?;
+  ensure!(status.success(), "editor exited with {}", status);
+
+  let result = std::fs::read_to_string(path).with_context(|| format!("failed to read {:?}", path))?;
+  std::fs::remove_file(path).with_context(|| format!("failed to remove {:?}", path))?;
+  Ok(result)
+}
+
 pub fn ssh_mux_path() -> String {
   format!("~/.ssh/pore_sshmux_{}_%r@%h:%p", std::process::id())
 }
@@ -222,3 +369,84 @@ pub fn ahead_behind(ahead: usize, behind: usize) -> String {
     (None, None) => "".to_string(),
   }
 }
+
+/// Check whether a repository is a partial clone, which might be missing objects that libgit2 can't fetch on demand.
+pub fn is_partial_clone(repo: &git2::Repository) -> bool {
+  repo
+    .config()
+    .and_then(|config| config.get_string("extensions.partialclone"))
+    .is_ok()
+}
+
+/// Check whether a repository only has part of its files checked out.
+pub fn is_sparse_checkout(repo: &git2::Repository) -> bool {
+  repo
+    .config()
+    .and_then(|config| config.get_bool("core.sparsecheckout"))
+    .unwrap_or(false)
+}
+
+/// Restrict the files checked out in a repository to those matching a list of patterns, or check out everything if
+/// there are no patterns.
+///
+/// Patterns use the format of `.git/info/sparse-checkout`, without cone mode. If `update` is false, only the
+/// configuration is changed, for repositories that haven't been checked out yet.
+pub fn set_sparse_checkout(repo: &git2::Repository, patterns: Option<&[String]>, update: bool) -> Result<(), Error> {
+  let sparse_checkout_path = repo.path().join("info").join("sparse-checkout");
+  let enabled = is_sparse_checkout(repo);
+  let mut config = repo.config().context("failed to get repository config")?;
+
+  match patterns {
+    None if !enabled => Ok(()),
+    None => {
+      if update {
+        run_git(repo, &["sparse-checkout", "disable"])
+      } else {
+        config
+          .set_bool("core.sparsecheckout", false)
+          .context("failed to disable sparse checkout")
+      }
+    }
+    Some(patterns) => {
+      let contents: String = patterns.iter().map(|pattern| format!("{}\n", pattern)).collect();
+      if enabled && std::fs::read_to_string(&sparse_checkout_path).ok().as_deref() == Some(contents.as_str()) {
+        return Ok(());
+      }
+
+      std::fs::create_dir_all(sparse_checkout_path.parent().unwrap())?;
+      std::fs::write(&sparse_checkout_path, contents)
+        .with_context(|| format!("failed to write {:?}", sparse_checkout_path))?;
+      config
+        .set_bool("core.sparsecheckout", true)
+        .context("failed to enable sparse checkout")?;
+      config
+        .set_bool("core.sparsecheckoutcone", false)
+        .context("failed to disable sparse checkout cone mode")?;
+
+      if update {
+        run_git(repo, &["sparse-checkout", "reapply"])
+      } else {
+        Ok(())
+      }
+    }
+  }
+}
+
+fn run_git(repo: &git2::Repository, args: &[&str]) -> Result<(), Error> {
+  let workdir = repo
+    .workdir()
+    .ok_or_else(|| format_err!("repository {:?} is bare", repo.path()))?;
+  let output = std::process::Command::new("git")
+    .current_dir(workdir)
+    .args(args)
+    .output()
+    .context("failed to spawn git")?;
+  if !output.status.success() {
+    bail!(
+      "git {} failed: {}",
+      args.join(" "),
+      String::from_utf8_lossy(&output.stderr)
+    );
+  }
+  Ok(())
+}
@@ -0,0 +1,175 @@
+/*
+ * Copyright (C) 2019 Josh Gao
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Helpers shared by the integration tests, which run pore against local bare repositories.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+pub fn command(program: &str, dir: &Path, root: &Path) -> Command {
+  let mut cmd = Command::new(program);
+  cmd
+    .current_dir(dir)
+    .env("HOME", root)
+    .env("GIT_CONFIG_NOSYSTEM", "1")
+    .env("GIT_AUTHOR_NAME", "A U Thor")
+    .env("GIT_AUTHOR_EMAIL", "author@example.com")
+    .env("GIT_COMMITTER_NAME", "C O Mitter")
+    .env("GIT_COMMITTER_EMAIL", "committer@example.com");
+  cmd
+}
+
+pub fn check(cmd: &mut Command) -> Output {
+  let output = cmd.output().expect("failed to spawn command");
+  assert!(
+    output.status.success(),
+    "{:?} failed:\n{}{}",
+    cmd,
+    String::from_utf8_lossy(&output.stdout),
+    String::from_utf8_lossy(&output.stderr)
+  );
+  output
+}
+
+/// A remote with a manifest and a single project called `project`, and a pore.toml that points at it.
+///
+/// The project starts out with a single commit on main. Tests can push more to it through the working copy at
+/// `project_work()` before cloning a tree with `clone_tree()`.
+pub struct TestRemote {
+  pub root: PathBuf,
+  pub tree: PathBuf,
+}
+
+impl TestRemote {
+  pub fn new(name: &str, files: &[(&str, &str)]) -> TestRemote {
+    let root = std::env::temp_dir().join(format!("pore-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&root).unwrap();
+    let root = std::fs::canonicalize(&root).unwrap();
+
+    let remote = TestRemote {
+      tree: root.join("tree"),
+      root,
+    };
+
+    for project in &["platform/manifest", "project"] {
+      let bare = remote.root.join("remote").join(format!("{}.git", project));
+      std::fs::create_dir_all(&bare).unwrap();
+      remote.git(&bare, &["init", "-q", "--bare"]);
+
+      let dir = remote.root.join("work").join(project);
+      std::fs::create_dir_all(&dir).unwrap();
+      remote.git(&dir, &["init", "-q"]);
+      remote.git(&dir, &["checkout", "-q", "-b", "main"]);
+    }
+
+    let manifest = remote.root.join("work/platform/manifest");
+    std::fs::write(
+      manifest.join("default.xml"),
+      "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+       <manifest>\n  \
+         <remote name=\"test\" fetch=\"..\" />\n  \
+         <default revision=\"main\" remote=\"test\" />\n  \
+         <project name=\"project\" path=\"project\" />\n\
+       </manifest>\n",
+    )
+    .unwrap();
+    remote.git(&manifest, &["add", "default.xml"]);
+    remote.git(&manifest, &["commit", "-q", "-m", "Add manifest"]);
+    remote.git(
+      &manifest,
+      &["push", "-q", &format!("{}platform/manifest.git", remote.url()), "main"],
+    );
+
+    let project = remote.project_work();
+    for (path, contents) in files {
+      let path = project.join(path);
+      std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+      std::fs::write(&path, contents).unwrap();
+    }
+    remote.git(&project, &["add", "."]);
+    remote.git(&project, &["commit", "-q", "-m", "Base"]);
+    remote.git(
+      &project,
+      &["push", "-q", &format!("{}project.git", remote.url()), "main"],
+    );
+
+    std::fs::write(
+      remote.root.join("pore.toml"),
+      format!(
+        "update_check = false\n\
+         \n\
+         [depots.test]\n\
+         path = '{}'\n\
+         \n\
+         [[remotes]]\n\
+         name = 'test'\n\
+         url = '{}'\n\
+         depot = 'test'\n\
+         \n\
+         [[manifests]]\n\
+         name = 'test'\n\
+         remote = 'test'\n\
+         project = 'platform/manifest'\n\
+         default_branch = 'main'\n",
+        remote.root.join("depot").display(),
+        remote.url()
+      ),
+    )
+    .unwrap();
+
+    remote
+  }
+
+  /// The URL of the remote, which projects are relative to.
+  pub fn url(&self) -> String {
+    format!("file://{}/", self.root.join("remote").display())
+  }
+
+  /// The working copy that the project on the remote was pushed from.
+  pub fn project_work(&self) -> PathBuf {
+    self.root.join("work/project")
+  }
+
+  /// Run git, returning its trimmed stdout.
+  pub fn git(&self, dir: &Path, args: &[&str]) -> String {
+    let output = check(command("git", dir, &self.root).args(args));
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+  }
+
+  pub fn clone_tree(&self) {
+    self.pore(&self.root, &["clone", "test/main", "tree"]);
+  }
+
+  pub fn pore_command(&self, dir: &Path, args: &[&str]) -> Command {
+    let mut cmd = command(env!("CARGO_BIN_EXE_pore"), dir, &self.root);
+    cmd.arg("--config").arg(self.root.join("pore.toml")).args(args);
+    cmd
+  }
+
+  pub fn pore(&self, dir: &Path, args: &[&str]) -> Output {
+    check(&mut self.pore_command(dir, args))
+  }
+}
+
+impl Drop for TestRemote {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.root);
+  }
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright (C) 2019 Josh Gao
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tests for `pore download`, against a local bare repository with Gerrit-style refs/changes refs.
+
+mod common;
+
+use common::TestRemote;
+
+/// A remote with a manifest and a single project, and a tree cloned from it.
+///
+/// The project has a base commit on main, change 1234 with two patchsets that each add a.txt, and change 1235 which
+/// adds b.txt.
+struct Fixture {
+  remote: TestRemote,
+  base: String,
+  change_1234_1: String,
+  change_1234_2: String,
+  change_1235_1: String,
+}
+
+impl Fixture {
+  fn new(name: &str) -> Fixture {
+    let remote = TestRemote::new(name, &[("base.txt", "base\n")]);
+    let project = remote.project_work();
+    let project_url = format!("{}project.git", remote.url());
+    let git = |args: &[&str]| remote.git(&project, args);
+    let base = git(&["rev-parse", "HEAD"]);
+
+    git(&["checkout", "-q", "-b", "change-1234"]);
+    std::fs::write(project.join("a.txt"), "patchset 1\n").unwrap();
+    git(&["add", "a.txt"]);
+    git(&["commit", "-q", "-m", "Add a.txt"]);
+    git(&["push", "-q", &project_url, "HEAD:refs/changes/34/1234/1"]);
+    let change_1234_1 = git(&["rev-parse", "HEAD"]);
+
+    std::fs::write(project.join("a.txt"), "patchset 2\n").unwrap();
+    git(&["commit", "-q", "-a", "--amend", "-m", "Add a.txt"]);
+    git(&["push", "-q", &project_url, "HEAD:refs/changes/34/1234/2"]);
+    let change_1234_2 = git(&["rev-parse", "HEAD"]);
+
+    // Gerrit also has a NoteDb ref for each change, which isn't a patchset.
+    git(&["push", "-q", &project_url, "main:refs/changes/34/1234/meta"]);
+
+    git(&["checkout", "-q", "-b", "change-1235", "main"]);
+    std::fs::write(project.join("b.txt"), "b\n").unwrap();
+    git(&["add", "b.txt"]);
+    git(&["commit", "-q", "-m", "Add b.txt"]);
+    git(&["push", "-q", &project_url, "HEAD:refs/changes/35/1235/1"]);
+    let change_1235_1 = git(&["rev-parse", "HEAD"]);
+
+    remote.clone_tree();
+    Fixture {
+      remote,
+      base,
+      change_1234_1,
+      change_1234_2,
+      change_1235_1,
+    }
+  }
+
+  fn pore_command(&self, args: &[&str]) -> std::process::Command {
+    self.remote.pore_command(&self.remote.tree, args)
+  }
+
+  fn download(&self, args: &[&str]) {
+    let mut full_args = vec!["download"];
+    full_args.extend(args);
+    self.remote.pore(&self.remote.tree, &full_args);
+  }
+
+  fn rev_parse(&self, rev: &str) -> String {
+    self.remote.git(&self.remote.tree.join("project"), &["rev-parse", rev])
+  }
+}
+
+#[test]
+fn download_checkout_latest_patchset() {
+  let fixture = Fixture::new("download-checkout-latest");
+  assert_eq!(fixture.rev_parse("HEAD"), fixture.base);
+
+  fixture.download(&["project", "1234"]);
+  assert_eq!(fixture.rev_parse("HEAD"), fixture.change_1234_2);
+}
+
+#[test]
+fn download_checkout_patchset() {
+  let fixture = Fixture::new("download-checkout-patchset");
+  fixture.download(&["project", "1234/1"]);
+  assert_eq!(fixture.rev_parse("HEAD"), fixture.change_1234_1);
+}
+
+#[test]
+fn download_missing_patchset() {
+  let fixture = Fixture::new("download-missing-patchset");
+  let output = fixture
+    .pore_command(&["download", "project", "1234/3"])
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+  assert_eq!(fixture.rev_parse("HEAD"), fixture.base);
+}
+
+#[test]
+fn download_cherry_pick() {
+  let fixture = Fixture::new("download-cherry-pick");
+  fixture.download(&["project", "1234/1"]);
+  fixture.download(&["-c", "project", "1235"]);
+
+  assert_eq!(fixture.rev_parse("HEAD^"), fixture.change_1234_1);
+  assert_ne!(fixture.rev_parse("HEAD"), fixture.change_1235_1);
+  assert_eq!(
+    fixture.rev_parse("HEAD:b.txt"),
+    fixture.rev_parse(&format!("{}:b.txt", fixture.change_1235_1))
+  );
+}
+
+#[test]
+fn download_revert() {
+  let fixture = Fixture::new("download-revert");
+  fixture.download(&["project", "1234"]);
+  fixture.download(&["-r", "project", "1234"]);
+
+  assert_eq!(fixture.rev_parse("HEAD^"), fixture.change_1234_2);
+  assert_eq!(
+    fixture.rev_parse("HEAD^{tree}"),
+    fixture.rev_parse(&format!("{}^{{tree}}", fixture.base))
+  );
+}
+
+#[test]
+fn download_fast_forward() {
+  let fixture = Fixture::new("download-fast-forward");
+  fixture.download(&["-f", "project", "1234"]);
+  assert_eq!(fixture.rev_parse("HEAD"), fixture.change_1234_2);
+
+  // Patchset 1 isn't a descendant of patchset 2, so this can't fast-forward.
+  let output = fixture
+    .pore_command(&["download", "-f", "project", "1234/1"])
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+  assert_eq!(fixture.rev_parse("HEAD"), fixture.change_1234_2);
+}
@@ -0,0 +1,63 @@
+/*
+ * Copyright (C) 2019 Josh Gao
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *      http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Tests for `pore grep`.
+
+mod common;
+
+use common::TestRemote;
+
+fn grep(name: &str, args: &[&str]) -> Vec<String> {
+  let remote = TestRemote::new(
+    name,
+    &[
+      ("top.txt", "needle\n"),
+      ("sub/inner.txt", "hay\nneedle\n"),
+      ("sub/deeper/more.txt", "needle\n"),
+      ("subway/inner.txt", "needle\n"),
+    ],
+  );
+  remote.clone_tree();
+
+  let mut full_args = vec!["grep"];
+  full_args.extend(args);
+  let output = remote.pore(&remote.tree, &full_args);
+  String::from_utf8_lossy(&output.stdout)
+    .lines()
+    .map(str::to_string)
+    .collect()
+}
+
+#[test]
+fn grep_project() {
+  assert_eq!(
+    grep("grep-project", &["needle", "project"]),
+    vec![
+      "project/sub/deeper/more.txt:1:needle",
+      "project/sub/inner.txt:2:needle",
+      "project/subway/inner.txt:1:needle",
+      "project/top.txt:1:needle",
+    ]
+  );
+}
+
+#[test]
+fn grep_subdirectory() {
+  assert_eq!(
+    grep("grep-subdirectory", &["needle", "project/sub"]),
+    vec!["project/sub/deeper/more.txt:1:needle", "project/sub/inner.txt:2:needle"]
+  );
+}